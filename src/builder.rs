@@ -0,0 +1,214 @@
+use std::cmp::Ordering;
+use crate::coordinate::Coordinate;
+use crate::node::check_root;
+use crate::quadtree::{Quadtree, QuadtreeError, Storage};
+
+/// Configures a [`Quadtree`] beyond what [`Quadtree::new`] offers.
+///
+/// Anything not set keeps its default: 8 entities per leaf, a depth of at
+/// most [`Coordinate::MAX_DEPTH`], cells no smaller than
+/// [`Coordinate::MIN_CELL_SIZE`], room for 128 entities, entity links and
+/// nodes, [`Storage::Split`], and a root that neither grows nor wraps.
+#[derive(Copy, Clone, Debug)]
+pub struct QuadtreeBuilder<S: Coordinate = f32> {
+    pub(crate) x: S,
+    pub(crate) y: S,
+    pub(crate) width: S,
+    pub(crate) height: S,
+    pub(crate) max_entities: u16,
+    pub(crate) max_depth: u8,
+    pub(crate) min_cell_size: S,
+    pub(crate) entity_capacity: usize,
+    pub(crate) entity_node_capacity: usize,
+    pub(crate) node_capacity: usize,
+    pub(crate) storage: Storage,
+    pub(crate) auto_grow: bool,
+    pub(crate) wrap: bool,
+}
+
+impl<S: Coordinate> QuadtreeBuilder<S> {
+    pub fn new(x: S, y: S, width: S, height: S) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+            max_entities: 8,
+            max_depth: S::MAX_DEPTH,
+            min_cell_size: S::MIN_CELL_SIZE,
+            entity_capacity: 128,
+            entity_node_capacity: 128,
+            node_capacity: 128,
+            storage: Storage::Split,
+            auto_grow: false,
+            wrap: false,
+        }
+    }
+
+    /// Sets how many entities a leaf holds before it is split.
+    pub fn max_entities(mut self, max_entities_per_region: u16) -> Self {
+        self.max_entities = max_entities_per_region;
+        self
+    }
+
+    /// Sets the deepest level nodes may be split down to. The root is at
+    /// depth 0, so a depth of 0 keeps the tree a single leaf.
+    pub fn max_depth(mut self, max_depth: u8) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Stops splitting nodes once their width or height is no longer larger
+    /// than `min_cell_size`. Whichever of this and the maximum depth is hit
+    /// first wins.
+    pub fn min_cell_size(mut self, min_cell_size: S) -> Self {
+        self.min_cell_size = min_cell_size;
+        self
+    }
+
+    /// Reserves room for this many entities up front.
+    pub fn entity_capacity(mut self, capacity: usize) -> Self {
+        self.entity_capacity = capacity;
+        self
+    }
+
+    /// Reserves room for this many links between entities and nodes up
+    /// front. With [`Storage::Split`] an entity has one link per leaf it
+    /// overlaps, otherwise exactly one.
+    pub fn entity_node_capacity(mut self, capacity: usize) -> Self {
+        self.entity_node_capacity = capacity;
+        self
+    }
+
+    /// Reserves room for this many nodes up front.
+    pub fn node_capacity(mut self, capacity: usize) -> Self {
+        self.node_capacity = capacity;
+        self
+    }
+
+    /// See [`Quadtree::with_storage`].
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// See [`Quadtree::set_auto_grow`].
+    pub fn auto_grow(mut self, enabled: bool) -> Self {
+        self.auto_grow = enabled;
+        self
+    }
+
+    /// See [`Quadtree::set_wrap`].
+    pub fn wrap(mut self, enabled: bool) -> Self {
+        self.wrap = enabled;
+        self
+    }
+
+    /// Checks the settings and creates the tree.
+    pub fn build<T>(&self) -> Result<Quadtree<T, S>, QuadtreeError> {
+        check_root([self.x, self.y], [self.width, self.height])?;
+        if self.max_entities == 0 {
+            return Err(QuadtreeError::ZeroMaxEntities);
+        }
+        // Comparing to NaN gives no ordering at all.
+        if let Some(Ordering::Less) | None = self.min_cell_size.partial_cmp(&S::default()) {
+            return Err(QuadtreeError::InvalidMinCellSize);
+        }
+        if let Storage::Loose(looseness) = self.storage {
+            if !(looseness.is_finite() && looseness >= 1.0) {
+                return Err(QuadtreeError::InvalidLooseness);
+            }
+        }
+        if self.wrap && self.storage != Storage::Split {
+            return Err(QuadtreeError::WrapRequiresSplitStorage);
+        }
+        if self.wrap && self.auto_grow {
+            return Err(QuadtreeError::WrapWithAutoGrow);
+        }
+        Ok(Quadtree::from_builder(self))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::TestVisitor;
+
+    fn deepest_leaf<T, S: Coordinate>(qt: &Quadtree<T, S>) -> u8 {
+        let mut tv = TestVisitor::new();
+        qt.traverse(&mut tv);
+        tv.leaves.into_iter().max().unwrap()
+    }
+
+    #[test]
+    fn defaults_match_new() -> Result<(), QuadtreeError> {
+        let built = QuadtreeBuilder::new(0, 0, 100, 100).build::<()>()?;
+        let new = Quadtree::<(), i32>::new(0, 0, 100, 100, 8)?;
+        assert_eq!(format!("{built:?}"), format!("{new:?}"));
+        Ok(())
+    }
+
+    #[test]
+    fn depth_limits() -> Result<(), QuadtreeError> {
+        // A 100 unit root would split 6 times on its own.
+        let builder = QuadtreeBuilder::new(0, 0, 100, 100).max_entities(1);
+        for (qt_builder, depth) in [
+            (builder, 6),
+            (builder.max_depth(3), 3),
+            (builder.min_cell_size(25), 2),
+            (builder.max_depth(1).min_cell_size(25), 1),
+            (builder.max_depth(0), 0),
+        ] {
+            // Coincident entities split the tree as deep as it may go.
+            let mut qt = qt_builder.build::<()>()?;
+            qt.insert(1, 1, 2, 2, ())?;
+            qt.insert(1, 1, 2, 2, ())?;
+            assert_eq!(deepest_leaf(&qt), depth);
+        }
+
+        // Floats can be stopped at any cell size.
+        let mut qt = QuadtreeBuilder::new(0.0, 0.0, 1.0, 1.0)
+            .max_entities(1)
+            .min_cell_size(0.1)
+            .build::<()>()?;
+        qt.insert(0.01, 0.01, 0.02, 0.02, ())?;
+        qt.insert(0.01, 0.01, 0.02, 0.02, ())?;
+        assert_eq!(deepest_leaf(&qt), 4);
+        Ok(())
+    }
+
+    #[test]
+    fn zero_capacities() -> Result<(), QuadtreeError> {
+        let mut qt = QuadtreeBuilder::new(0, 0, 100, 100)
+            .max_entities(1)
+            .entity_capacity(0)
+            .entity_node_capacity(0)
+            .node_capacity(0)
+            .build()?;
+        for i in 0..10 {
+            qt.insert(i * 5, i * 5, i * 5 + 3, i * 5 + 3, i)?;
+        }
+        assert_eq!(qt.query(-50, -50, 50, 50)?.len(), 10);
+        Ok(())
+    }
+
+    #[test]
+    fn validation() {
+        let builder = QuadtreeBuilder::new(0.0, 0.0, 100.0, 100.0);
+        let errors = [
+            (builder.max_entities(0), QuadtreeError::ZeroMaxEntities),
+            (builder.min_cell_size(-1.0), QuadtreeError::InvalidMinCellSize),
+            (builder.min_cell_size(f32::NAN), QuadtreeError::InvalidMinCellSize),
+            (builder.storage(Storage::Loose(0.5)), QuadtreeError::InvalidLooseness),
+            (
+                builder.storage(Storage::Enclosing).wrap(true),
+                QuadtreeError::WrapRequiresSplitStorage,
+            ),
+            (builder.auto_grow(true).wrap(true), QuadtreeError::WrapWithAutoGrow),
+        ];
+        for (builder, error) in errors {
+            assert_eq!(builder.build::<()>().err(), Some(error));
+        }
+        assert!(builder.storage(Storage::Loose(2.0)).auto_grow(true).build::<()>().is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt::Debug;
+use std::ops::{Add, Sub};
+
+/// A scalar type usable for quadtree coordinates.
+pub trait Coordinate:
+    Copy + Debug + Default + PartialOrd + Add<Output = Self> + Sub<Output = Self>
+{
+    /// The smallest region size the tree will subdivide down to.
+    ///
+    /// This is 2 for integers, as it always was, but 0 for floats, whose
+    /// depth is only bounded by [`Coordinate::MAX_DEPTH`]. Float trees
+    /// used to be truncated to integers and stop at cells of 2 units, so a
+    /// 100 unit `f32` tree may now split 16 levels deep instead of 6. Use
+    /// [`crate::QuadtreeBuilder::min_cell_size`] to get the old limit back.
+    const MIN_CELL_SIZE: Self;
+    /// The deepest the tree will subdivide regardless of the region size.
+    const MAX_DEPTH: u8;
+
+    fn half(self) -> Self;
+    fn to_f64(self) -> f64;
+    /// The remainder of dividing by `rhs`, which is never negative.
+    fn rem_euclid(self, rhs: Self) -> Self;
+    /// Returns false for NaN and infinite values.
+    fn is_finite(self) -> bool;
+    /// Adds `rhs`, or returns `None` if the sum does not fit the type.
+    fn checked_add(self, rhs: Self) -> Option<Self>;
+    /// Subtracts `rhs`, or returns `None` if the difference does not fit
+    /// the type.
+    fn checked_sub(self, rhs: Self) -> Option<Self>;
+}
+
+macro_rules! impl_integer_coordinate {
+    ($($t:ty),*) => {$(
+        impl Coordinate for $t {
+            const MIN_CELL_SIZE: Self = 2;
+            const MAX_DEPTH: u8 = u8::MAX;
+
+            fn half(self) -> Self {
+                self / 2
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn rem_euclid(self, rhs: Self) -> Self {
+                <$t>::rem_euclid(self, rhs)
+            }
+
+            fn is_finite(self) -> bool {
+                true
+            }
+
+            fn checked_add(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_add(self, rhs)
+            }
+
+            fn checked_sub(self, rhs: Self) -> Option<Self> {
+                <$t>::checked_sub(self, rhs)
+            }
+        }
+    )*};
+}
+
+macro_rules! impl_float_coordinate {
+    ($($t:ty => $max_depth:expr),*) => {$(
+        impl Coordinate for $t {
+            // Floats can keep subdividing, so the depth is only bounded
+            // by how many halvings the mantissa can represent usefully.
+            const MIN_CELL_SIZE: Self = 0.0;
+            const MAX_DEPTH: u8 = $max_depth;
+
+            fn half(self) -> Self {
+                self * 0.5
+            }
+
+            fn to_f64(self) -> f64 {
+                self as f64
+            }
+
+            fn rem_euclid(self, rhs: Self) -> Self {
+                <$t>::rem_euclid(self, rhs)
+            }
+
+            fn is_finite(self) -> bool {
+                <$t>::is_finite(self)
+            }
+
+            // Floats round instead of wrapping, so a result only fails to
+            // fit once it becomes infinite.
+            fn checked_add(self, rhs: Self) -> Option<Self> {
+                Some(self + rhs).filter(|sum| sum.is_finite())
+            }
+
+            fn checked_sub(self, rhs: Self) -> Option<Self> {
+                Some(self - rhs).filter(|difference| difference.is_finite())
+            }
+        }
+    )*};
+}
+
+impl_integer_coordinate!(i32, i64);
+impl_float_coordinate!(f32 => 16, f64 => 32);
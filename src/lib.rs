@@ -1,5 +1,18 @@
+mod builder;
+mod coordinate;
 mod list;
+mod node;
+mod octree;
+mod point_quadtree;
 mod quadtree;
+mod svg;
+#[cfg(test)]
+mod test_util;
 
+pub use builder::*;
+pub use coordinate::*;
 pub use list::*;
+pub use octree::*;
+pub use point_quadtree::*;
 pub use quadtree::*;
+pub use svg::*;
@@ -9,6 +9,8 @@ where
     cursor: usize,
     capacity: usize,
     vacant: Vec<usize>,
+    generations: Vec<u32>,
+    occupied: Vec<bool>,
 }
 
 impl<T> List<T>
@@ -23,6 +25,8 @@ where
             capacity,
             cursor: 0,
             vacant: Vec::new(),
+            generations: vec![0; capacity],
+            occupied: vec![false; capacity],
         }
     }
 
@@ -30,6 +34,24 @@ where
         self.cursor
     }
 
+    /// Returns the number of erased slots waiting to be reused.
+    pub fn vacancies(&self) -> usize {
+        self.vacant.len()
+    }
+
+    /// Returns the generation of the slot at `index`. The generation is
+    /// bumped every time the slot is erased, so it can be used to tell a
+    /// reused slot apart from the element that previously occupied it.
+    pub fn generation(&self, index: usize) -> u32 {
+        self.generations[index]
+    }
+
+    /// Returns true if the slot at `index` holds an element, that is it was
+    /// handed out and has not been erased, popped or cleared since.
+    pub fn is_occupied(&self, index: usize) -> bool {
+        index < self.cursor && self.occupied[index]
+    }
+
     pub fn get(&self, index: usize) -> &T {
         debug_assert!(index < self.cursor);
         &self.data[index]
@@ -46,6 +68,10 @@ where
     }
 
     pub fn clear(&mut self) {
+        for generation in &mut self.generations[..self.cursor] {
+            *generation = generation.wrapping_add(1);
+        }
+        self.occupied[..self.cursor].fill(false);
         self.cursor = 0;
         self.vacant.clear();
     }
@@ -53,19 +79,23 @@ where
     pub fn push(&mut self, element: T) -> usize {
         let new_pos = self.cursor + 1;
         if new_pos > self.capacity {
-            let new_cap = self.cursor * 2;
+            let new_cap = (self.cursor * 2).max(1);
             self.data.resize(new_cap, T::default());
+            self.generations.resize(new_cap, 0);
+            self.occupied.resize(new_cap, false);
             self.capacity = new_cap
         }
         let index = self.cursor;
         self.cursor += 1;
         self.data[index] = element;
+        self.occupied[index] = true;
         index
     }
 
     pub fn pop(&mut self) -> T {
         debug_assert!(self.cursor > 0);
         self.cursor -= 1;
+        self.occupied[self.cursor] = false;
         self.data[self.cursor]
     }
 
@@ -73,13 +103,21 @@ where
         match self.vacant.pop() {
             Some(vacant) => {
                 self.data[vacant] = element;
+                self.occupied[vacant] = true;
                 vacant
             }
             None => self.push(element),
         }
     }
 
+    /// Frees the slot at `index` for reuse. Erasing a slot that is not
+    /// occupied does nothing, so it is never handed out twice.
     pub fn erase(&mut self, index: usize) {
+        if !self.is_occupied(index) {
+            return;
+        }
+        self.occupied[index] = false;
+        self.generations[index] = self.generations[index].wrapping_add(1);
         self.vacant.push(index);
     }
 }
@@ -163,6 +201,38 @@ mod tests {
         assert_eq!(list.capacity, 4);
     }
 
+    #[test]
+    fn occupancy() {
+        let mut list = List::<u8>::new(2);
+        let a = list.insert(1);
+        let b = list.insert(2);
+        assert!(list.is_occupied(a) && list.is_occupied(b));
+        assert!(!list.is_occupied(2));
+
+        // Erasing twice only frees the slot once.
+        list.erase(a);
+        list.erase(a);
+        assert!(!list.is_occupied(a));
+        assert_eq!(list.vacancies(), 1);
+        assert_eq!(list.generation(a), 1);
+        assert_eq!(list.insert(3), a);
+        assert_eq!(list.insert(4), 2);
+        assert!(list.is_occupied(a));
+
+        list.pop();
+        assert!(!list.is_occupied(2));
+        list.clear();
+        assert!(!list.is_occupied(a) && !list.is_occupied(b));
+    }
+
+    #[test]
+    fn zero_capacity() {
+        let mut list = List::<u8>::new(0);
+        assert_eq!(list.push(1), 0);
+        assert_eq!(list.push(2), 1);
+        assert_eq!(list.capacity, 2);
+    }
+
     #[test]
     fn vacant() {
         let mut list = List::<u8>::default();
@@ -179,4 +249,30 @@ mod tests {
             assert_eq!(x, y);
         }
     }
+
+    #[test]
+    fn generation() {
+        let mut list = List::<u8>::new(2);
+        let a = list.insert(1);
+        let b = list.insert(2);
+        assert_eq!(list.generation(a), 0);
+        assert_eq!(list.generation(b), 0);
+
+        list.erase(a);
+        assert_eq!(list.generation(a), 1);
+        assert_eq!(list.generation(b), 0);
+
+        // Reusing the slot keeps the bumped generation.
+        assert_eq!(list.insert(3), a);
+        assert_eq!(list.generation(a), 1);
+
+        // Growing the list starts new slots at generation 0.
+        let c = list.insert(4);
+        assert_eq!(list.generation(c), 0);
+
+        list.clear();
+        assert_eq!(list.generation(a), 2);
+        assert_eq!(list.generation(b), 1);
+        assert_eq!(list.generation(c), 1);
+    }
 }
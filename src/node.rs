@@ -0,0 +1,234 @@
+use std::fmt::Debug;
+use crate::coordinate::Coordinate;
+use crate::list::List;
+use crate::quadtree::{EntityHandle, QuadtreeError};
+
+/// A node of a tree. Branches have their children stored contiguously from
+/// `first_child`, leaves have none. Any node may hold entities, though
+/// only leaves do with [`crate::Storage::Split`].
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Node {
+    pub(crate) first_child: Option<usize>,
+    pub(crate) first_entity: Option<usize>,
+    pub(crate) num_entities: usize,
+}
+
+/// A link between a node and one of the entities it holds.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct EntityNode {
+    pub(crate) next: Option<usize>,
+    pub(crate) entity: usize,
+}
+
+/// Checks the center and size of a root along each axis and returns its
+/// half sizes. Fails if they are not finite, if the root is empty, or if
+/// its edges or its full size do not fit the coordinate type.
+pub(crate) fn check_root<S: Coordinate, const N: usize>(
+    center: [S; N],
+    size: [S; N],
+) -> Result<[S; N], QuadtreeError> {
+    if !center.into_iter().chain(size).all(S::is_finite) {
+        return Err(QuadtreeError::NonFiniteCoordinate);
+    }
+    // Integer roots narrower than 2 units would have no area left.
+    let half = size.map(S::half);
+    if half.iter().any(|h| *h <= S::default()) {
+        return Err(QuadtreeError::InvalidRootSize);
+    }
+    for (c, h) in center.into_iter().zip(half) {
+        c.checked_sub(h)
+            .and(c.checked_add(h))
+            .and(h.checked_add(h))
+            .ok_or(QuadtreeError::CoordinateOverflow)?;
+    }
+    Ok(half)
+}
+
+/// Tells apart handles to slots the tree has handed out, whose entity is
+/// gone when the generation no longer matches, from handles past any slot.
+/// Generations wrap around, so only equality between them is meaningful.
+pub(crate) fn check_handle<E>(entities: &List<E>, entity: EntityHandle) -> Result<(), QuadtreeError>
+where
+    E: Copy + Debug + Default,
+{
+    if entity.index >= entities.cursor() {
+        return Err(QuadtreeError::EntityNotFound);
+    }
+    let generation = entities.generation(entity.index);
+    if entity.generation == generation && entities.is_occupied(entity.index) {
+        Ok(())
+    } else {
+        Err(QuadtreeError::EntityAlreadyRemoved)
+    }
+}
+
+/// Links an entity into a node without ever splitting it.
+pub(crate) fn link(
+    nodes: &mut List<Node>,
+    entity_nodes: &mut List<EntityNode>,
+    node_idx: usize,
+    entity_idx: usize,
+) {
+    let first_entity = nodes.get(node_idx).first_entity;
+    let e_node = entity_nodes.insert(EntityNode {
+        entity: entity_idx,
+        next: first_entity,
+    });
+    let node = nodes.get_mut(node_idx);
+    node.first_entity = Some(e_node);
+    node.num_entities += 1;
+}
+
+pub(crate) fn unlink(
+    nodes: &mut List<Node>,
+    entity_nodes: &mut List<EntityNode>,
+    node_idx: usize,
+    entity_idx: usize,
+) {
+    // Walk the list until we find the element node.
+    let mut enode_idx = nodes.get(node_idx).first_entity;
+    let mut prev_index = None;
+    while let Some(idx) = enode_idx {
+        if entity_nodes.get(idx).entity == entity_idx {
+            break;
+        }
+        prev_index = enode_idx;
+        enode_idx = entity_nodes.get(idx).next;
+    }
+
+    if let Some(idx) = enode_idx {
+        // Remove the element node.
+        let next_index = entity_nodes.get(idx).next;
+        match prev_index {
+            None => nodes.get_mut(node_idx).first_entity = next_index,
+            Some(prev) => entity_nodes.get_mut(prev).next = next_index,
+        }
+        entity_nodes.erase(idx);
+
+        // Decrement the node element count.
+        nodes.get_mut(node_idx).num_entities -= 1;
+    }
+}
+
+/// Turns the leaf `node_idx` into a branch with `num_children` empty
+/// children and returns the entities it held, which the caller must link
+/// again.
+pub(crate) fn split(
+    nodes: &mut List<Node>,
+    entity_nodes: &mut List<EntityNode>,
+    node_idx: usize,
+    num_children: usize,
+) -> List<usize> {
+    // Transfer elements from the leaf node to a list of elements.
+    let mut entities = List::<usize>::default();
+    while let Some(enode_idx) = nodes.get(node_idx).first_entity {
+        let e_node = *entity_nodes.get(enode_idx);
+        nodes.get_mut(node_idx).first_entity = e_node.next;
+        entity_nodes.erase(enode_idx);
+        entities.push(e_node.entity);
+    }
+
+    let fc = nodes.insert(Node::default());
+    for _ in 1..num_children {
+        nodes.insert(Node::default());
+    }
+    *nodes.get_mut(node_idx) = Node {
+        first_child: Some(fc),
+        ..Node::default()
+    };
+    entities
+}
+
+/// Returns the branches at and below `node_idx`, parents before their
+/// children.
+pub(crate) fn branches(nodes: &List<Node>, node_idx: usize, num_children: usize) -> List<usize> {
+    let mut branches = List::<usize>::default();
+    let mut to_process = List::<usize>::default();
+    if nodes.get(node_idx).first_child.is_some() {
+        to_process.push(node_idx);
+    }
+    while to_process.cursor() > 0 {
+        let node_idx = to_process.pop();
+        branches.push(node_idx);
+        let fc = nodes.get(node_idx).first_child.unwrap();
+        for child_idx in fc..fc + num_children {
+            if nodes.get(child_idx).first_child.is_some() {
+                to_process.push(child_idx);
+            }
+        }
+    }
+    branches
+}
+
+/// Returns true if every child of the branch `node_idx` is a leaf.
+pub(crate) fn has_only_leaves(nodes: &List<Node>, node_idx: usize, num_children: usize) -> bool {
+    let fc = nodes.get(node_idx).first_child.unwrap();
+    (fc..fc + num_children).all(|i| nodes.get(i).first_child.is_none())
+}
+
+/// Returns the unique entities held by the branch `node_idx` and its leaf
+/// children, or `None` if there are more of them than `max_entities`.
+pub(crate) fn unique_leaf_entities(
+    nodes: &List<Node>,
+    entity_nodes: &List<EntityNode>,
+    node_idx: usize,
+    num_children: usize,
+    max_entities: u16,
+) -> Option<List<usize>> {
+    let mut entities = List::<usize>::default();
+    let fc = nodes.get(node_idx).first_child.unwrap();
+    for child_idx in std::iter::once(node_idx).chain(fc..fc + num_children) {
+        let mut next_enode_idx = nodes.get(child_idx).first_entity;
+        while let Some(enode_idx) = next_enode_idx {
+            let entity_node = entity_nodes.get(enode_idx);
+            let is_new = (0..entities.cursor()).all(|i| *entities.get(i) != entity_node.entity);
+            if is_new {
+                if entities.cursor() == max_entities as usize {
+                    return None;
+                }
+                entities.push(entity_node.entity);
+            }
+            next_enode_idx = entity_node.next;
+        }
+    }
+    Some(entities)
+}
+
+/// Turns the branch `node_idx` back into a leaf holding `entities`,
+/// dropping its leaf children along with all their entity lists.
+pub(crate) fn collapse(
+    nodes: &mut List<Node>,
+    entity_nodes: &mut List<EntityNode>,
+    node_idx: usize,
+    num_children: usize,
+    entities: &List<usize>,
+) {
+    let fc = nodes.get(node_idx).first_child.unwrap();
+    for child_idx in std::iter::once(node_idx).chain(fc..fc + num_children) {
+        let mut next_enode_idx = nodes.get(child_idx).first_entity;
+        while let Some(enode_idx) = next_enode_idx {
+            next_enode_idx = entity_nodes.get(enode_idx).next;
+            entity_nodes.erase(enode_idx);
+        }
+    }
+
+    // Remove the children in reverse order so that they can be
+    // reclaimed on subsequent insertions in proper order.
+    for child_idx in (fc..fc + num_children).rev() {
+        nodes.erase(child_idx);
+    }
+
+    // Make this node the new leaf, linking each entity once.
+    let mut first_entity = None;
+    for i in 0..entities.cursor() {
+        first_entity = Some(entity_nodes.insert(EntityNode {
+            entity: *entities.get(i),
+            next: first_entity,
+        }));
+    }
+    *nodes.get_mut(node_idx) = Node {
+        first_child: None,
+        first_entity,
+        num_entities: entities.cursor(),
+    };
+}
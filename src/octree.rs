@@ -0,0 +1,639 @@
+use crate::coordinate::Coordinate;
+use crate::list::List;
+use crate::node::{self, EntityNode, Node};
+use crate::quadtree::{EntityHandle, QuadtreeError};
+
+#[allow(clippy::too_many_arguments)]
+pub trait OctreeVisitor<T, S: Coordinate> {
+    fn entity(
+        &mut self,
+        entity: EntityHandle,
+        payload: &T,
+        idx: usize,
+        next_entity: Option<usize>,
+        x: S,
+        y: S,
+        z: S,
+        width: S,
+        height: S,
+        depth: S,
+    );
+    fn leaf(
+        &mut self,
+        level: u8,
+        idx: usize,
+        num_entities: usize,
+        first_entity: Option<usize>,
+        x: S,
+        y: S,
+        z: S,
+        width: S,
+        height: S,
+        depth: S,
+    );
+    fn branch(
+        &mut self,
+        level: u8,
+        idx: usize,
+        first_leaf: usize,
+        x: S,
+        y: S,
+        z: S,
+        width: S,
+        height: S,
+        depth: S,
+    );
+}
+
+/// An axis aligned box. `front` and `back` bound the z axis.
+#[derive(Copy, Clone, Debug, Default)]
+struct Cuboid<S: Coordinate> {
+    left: S,
+    top: S,
+    front: S,
+    right: S,
+    bottom: S,
+    back: S,
+}
+
+impl<S: Coordinate> Cuboid<S> {
+    /// Returns one octant of the box split at `(x, y, z)`.
+    fn split(&self, x: S, y: S, z: S, octant: usize) -> Self {
+        let (left, right) = match octant & 1 {
+            0 => (self.left, x),
+            _ => (x, self.right),
+        };
+        let (top, bottom) = match octant & 2 {
+            0 => (self.top, y),
+            _ => (y, self.bottom),
+        };
+        let (front, back) = match octant & 4 {
+            0 => (self.front, z),
+            _ => (z, self.back),
+        };
+        Self {
+            left,
+            top,
+            front,
+            right,
+            bottom,
+            back,
+        }
+    }
+
+    /// Like [`crate::Quadtree`] regions, boxes on a shared edge go to the
+    /// left, top or front side only.
+    fn receives(&self, root: &Cuboid<S>, other: &Cuboid<S>) -> bool {
+        other.left <= self.right
+            && (other.right > self.left || self.left == root.left)
+            && other.top <= self.bottom
+            && (other.bottom > self.top || self.top == root.top)
+            && other.front <= self.back
+            && (other.back > self.front || self.front == root.front)
+    }
+
+    fn intersects(&self, other: &Cuboid<S>) -> bool {
+        other.left <= self.right
+            && other.right >= self.left
+            && other.top <= self.bottom
+            && other.bottom >= self.top
+            && other.front <= self.back
+            && other.back >= self.front
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct NodeData<S: Coordinate> {
+    idx: usize,
+    level: u8,
+    x: S,
+    y: S,
+    z: S,
+    hx: S,
+    hy: S,
+    hz: S,
+    region: Cuboid<S>,
+}
+
+impl<S: Coordinate> NodeData<S> {
+    fn root(idx: usize, x: S, y: S, z: S, hx: S, hy: S, hz: S) -> Self {
+        Self {
+            idx,
+            level: 0,
+            x,
+            y,
+            z,
+            hx,
+            hy,
+            hz,
+            region: Cuboid {
+                left: x - hx,
+                top: y - hy,
+                front: z - hz,
+                right: x + hx,
+                bottom: y + hy,
+                back: z + hz,
+            },
+        }
+    }
+
+    /// Bit 0 of the octant selects the right half, bit 1 the bottom and
+    /// bit 2 the back.
+    fn child(&self, first_child: usize, octant: usize) -> Self {
+        let qx = self.hx.half();
+        let qy = self.hy.half();
+        let qz = self.hz.half();
+        Self {
+            idx: first_child + octant,
+            level: self.level + 1,
+            x: match octant & 1 {
+                0 => self.x - qx,
+                _ => self.x + qx,
+            },
+            y: match octant & 2 {
+                0 => self.y - qy,
+                _ => self.y + qy,
+            },
+            z: match octant & 4 {
+                0 => self.z - qz,
+                _ => self.z + qz,
+            },
+            hx: qx,
+            hy: qy,
+            hz: qz,
+            region: self.region.split(self.x, self.y, self.z, octant),
+        }
+    }
+}
+
+/// The 3D counterpart of [`crate::Quadtree`] with split storage.
+#[derive(Clone, Debug)]
+pub struct Octree<T, S: Coordinate = f32> {
+    root: NodeData<S>,
+    max_entities: u16,
+    max_level: u8,
+    entity_nodes: List<EntityNode>,
+    entities: List<Cuboid<S>>,
+    payloads: Vec<Option<T>>,
+    nodes: List<Node>,
+}
+
+impl<T, S: Coordinate> Octree<T, S> {
+    /// Creates a tree centered at `(x, y, z)`, checked like [`crate::Quadtree::new`].
+    pub fn new(
+        x: S,
+        y: S,
+        z: S,
+        width: S,
+        height: S,
+        depth: S,
+        max_entities_per_region: u16,
+    ) -> Result<Self, QuadtreeError> {
+        let [hx, hy, hz] = node::check_root([x, y, z], [width, height, depth])?;
+        if max_entities_per_region == 0 {
+            return Err(QuadtreeError::ZeroMaxEntities);
+        }
+        let mut nodes = List::default();
+        let root_idx = nodes.insert(Node::default());
+        Ok(Self {
+            root: NodeData::root(root_idx, x, y, z, hx, hy, hz),
+            max_entities: max_entities_per_region,
+            max_level: Self::calc_max_level(width, height, depth),
+            nodes,
+            entity_nodes: List::default(),
+            entities: List::default(),
+            payloads: Vec::new(),
+        })
+    }
+
+    fn calc_max_level(w: S, h: S, d: S) -> u8 {
+        let mut level: u8 = 0;
+        let mut size = if w <= h { w } else { h };
+        if d < size {
+            size = d;
+        }
+        while size > S::MIN_CELL_SIZE && level < S::MAX_DEPTH {
+            size = size.half();
+            level += 1;
+        }
+        level
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn insert(
+        &mut self,
+        x1: S,
+        y1: S,
+        z1: S,
+        x2: S,
+        y2: S,
+        z2: S,
+        payload: T,
+    ) -> Result<EntityHandle, QuadtreeError> {
+        let entity = Cuboid {
+            left: x1,
+            top: y1,
+            front: z1,
+            right: x2,
+            bottom: y2,
+            back: z2,
+        };
+        self.check_coordinates(&entity)?;
+        self.check_bounds(&entity)?;
+        let new_entity_idx = self.entities.insert(entity);
+        if new_entity_idx == self.payloads.len() {
+            self.payloads.push(Some(payload));
+        } else {
+            self.payloads[new_entity_idx] = Some(payload);
+        }
+        self.node_insert(self.root, new_entity_idx);
+        Ok(self.handle(new_entity_idx))
+    }
+
+    /// Returns true if the handle refers to an entity that is still in the tree.
+    pub fn contains(&self, entity: EntityHandle) -> bool {
+        node::check_handle(&self.entities, entity).is_ok()
+    }
+
+    pub fn get(&self, entity: EntityHandle) -> Option<&T> {
+        match self.contains(entity) {
+            true => self.payloads[entity.index].as_ref(),
+            false => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, entity: EntityHandle) -> Option<&mut T> {
+        match self.contains(entity) {
+            true => self.payloads[entity.index].as_mut(),
+            false => None,
+        }
+    }
+
+    /// Removes an entity, returning its payload.
+    pub fn remove(&mut self, entity: EntityHandle) -> Result<T, QuadtreeError> {
+        node::check_handle(&self.entities, entity)?;
+        let entity_idx = entity.index;
+        let payload = self.payloads[entity_idx]
+            .take()
+            .expect("entity in the tree has no payload");
+
+        let leaves = self.find_leaves(self.root, self.entities.get(entity_idx));
+        for i in 0..leaves.cursor() {
+            node::unlink(&mut self.nodes, &mut self.entity_nodes, leaves.get(i).idx, entity_idx);
+        }
+        self.entities.erase(entity_idx);
+        Ok(payload)
+    }
+
+    /// Collapses branches like [`crate::Quadtree::cleanup`].
+    pub fn cleanup(&mut self) {
+        // Walking the branches backwards visits children before their parent.
+        let mut branches = node::branches(&self.nodes, self.root.idx, 8);
+        while branches.cursor() > 0 {
+            let node_idx = branches.pop();
+            if node::has_only_leaves(&self.nodes, node_idx, 8) {
+                let unique = node::unique_leaf_entities(
+                    &self.nodes,
+                    &self.entity_nodes,
+                    node_idx,
+                    8,
+                    self.max_entities,
+                );
+                if let Some(entities) = unique {
+                    node::collapse(&mut self.nodes, &mut self.entity_nodes, node_idx, 8, &entities);
+                }
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn query(
+        &self,
+        x1: S,
+        y1: S,
+        z1: S,
+        x2: S,
+        y2: S,
+        z2: S,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
+        self.query_omit(x1, y1, z1, x2, y2, z2, None)
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub fn query_omit(
+        &self,
+        x1: S,
+        y1: S,
+        z1: S,
+        x2: S,
+        y2: S,
+        z2: S,
+        omit_entity: Option<EntityHandle>,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
+        let mut out = Vec::<(EntityHandle, &T)>::new();
+        let query = Cuboid {
+            left: x1,
+            top: y1,
+            front: z1,
+            right: x2,
+            bottom: y2,
+            back: z2,
+        };
+        self.check_coordinates(&query)?;
+        let mut seen = Vec::<bool>::new();
+        seen.resize(self.entities.cursor(), false);
+
+        let leaves = self.find_leaves(self.root, &query);
+        for i in 0..leaves.cursor() {
+            let mut next_enode_idx = self.nodes.get(leaves.get(i).idx).first_entity;
+            while let Some(enode_idx) = next_enode_idx {
+                let entity_node = self.entity_nodes.get(enode_idx);
+                if !seen[entity_node.entity]
+                    && omit_entity != Some(self.handle(entity_node.entity))
+                    && query.intersects(self.entities.get(entity_node.entity))
+                {
+                    seen[entity_node.entity] = true;
+                    out.push((self.handle(entity_node.entity), self.payload(entity_node.entity)));
+                }
+                next_enode_idx = entity_node.next;
+            }
+        }
+        Ok(out)
+    }
+
+    pub fn traverse(&self, visitor: &mut impl OctreeVisitor<T, S>) {
+        let mut to_process = List::<NodeData<S>>::default();
+        to_process.push(self.root);
+
+        while to_process.cursor() > 0 {
+            let nd_data = to_process.pop();
+            let (w, h, d) = (nd_data.hx + nd_data.hx, nd_data.hy + nd_data.hy, nd_data.hz + nd_data.hz);
+            let node = self.nodes.get(nd_data.idx);
+            if let Some(fc) = node.first_child {
+                for octant in 0..8 {
+                    to_process.push(nd_data.child(fc, octant));
+                }
+                visitor.branch(nd_data.level, nd_data.idx, fc, nd_data.x, nd_data.y, nd_data.z, w, h, d);
+                continue;
+            }
+
+            visitor.leaf(
+                nd_data.level,
+                nd_data.idx,
+                node.num_entities,
+                node.first_entity,
+                nd_data.x,
+                nd_data.y,
+                nd_data.z,
+                w,
+                h,
+                d,
+            );
+            let mut next_enode_idx = node.first_entity;
+            while let Some(enode_idx) = next_enode_idx {
+                let entity_node = self.entity_nodes.get(enode_idx);
+                let entity = self.entities.get(entity_node.entity);
+                let w = entity.right - entity.left;
+                let h = entity.bottom - entity.top;
+                let d = entity.back - entity.front;
+                visitor.entity(
+                    self.handle(entity_node.entity),
+                    self.payload(entity_node.entity),
+                    enode_idx,
+                    entity_node.next,
+                    entity.left + w.half(),
+                    entity.top + h.half(),
+                    entity.front + d.half(),
+                    w,
+                    h,
+                    d,
+                );
+                next_enode_idx = entity_node.next;
+            }
+        }
+    }
+
+    /// Rejects boxes that are not finite or too large to measure.
+    fn check_coordinates(&self, entity: &Cuboid<S>) -> Result<(), QuadtreeError> {
+        let Cuboid { left, top, front, right, bottom, back } = *entity;
+        if ![left, top, front, right, bottom, back].into_iter().all(S::is_finite) {
+            return Err(QuadtreeError::NonFiniteCoordinate);
+        }
+        right
+            .checked_sub(left)
+            .and(bottom.checked_sub(top))
+            .and(back.checked_sub(front))
+            .map(|_| ())
+            .ok_or(QuadtreeError::CoordinateOverflow)
+    }
+
+    fn check_bounds(&self, entity: &Cuboid<S>) -> Result<(), QuadtreeError> {
+        if entity.left >= entity.right || entity.top >= entity.bottom || entity.front >= entity.back {
+            return Err(QuadtreeError::InsertHasInvertedBounds);
+        }
+        let root = &self.root.region;
+        if entity.left < root.left
+            || entity.right > root.right
+            || entity.top < root.top
+            || entity.bottom > root.bottom
+            || entity.front < root.front
+            || entity.back > root.back
+        {
+            return Err(QuadtreeError::InsertIsOutOfBounds);
+        }
+        Ok(())
+    }
+
+    fn handle(&self, entity_idx: usize) -> EntityHandle {
+        EntityHandle {
+            index: entity_idx,
+            generation: self.entities.generation(entity_idx),
+        }
+    }
+
+    fn payload(&self, entity_idx: usize) -> &T {
+        self.payloads[entity_idx]
+            .as_ref()
+            .expect("entity in the tree has no payload")
+    }
+
+    /// Returns the leaves below `start_node` that a box is routed into.
+    fn find_leaves(&self, start_node: NodeData<S>, entity: &Cuboid<S>) -> List<NodeData<S>> {
+        let mut leaves = List::<NodeData<S>>::default();
+        let mut to_process = List::<NodeData<S>>::default();
+        to_process.push(start_node);
+
+        while to_process.cursor() > 0 {
+            let nd_data = to_process.pop();
+            match self.nodes.get(nd_data.idx).first_child {
+                None => {
+                    leaves.push(nd_data);
+                }
+                Some(fc) => {
+                    for octant in 0..8 {
+                        let child = nd_data.child(fc, octant);
+                        if child.region.receives(&self.root.region, entity) {
+                            to_process.push(child);
+                        }
+                    }
+                }
+            }
+        }
+        leaves
+    }
+
+    fn node_insert(&mut self, start_node: NodeData<S>, entity_idx: usize) {
+        let leaves = self.find_leaves(start_node, self.entities.get(entity_idx));
+        for i in 0..leaves.cursor() {
+            self.leaf_insert(*leaves.get(i), entity_idx);
+        }
+    }
+
+    fn leaf_insert(&mut self, node_data: NodeData<S>, entity_idx: usize) {
+        let first_entity = self.nodes.get(node_data.idx).first_entity;
+        let e_node = self.entity_nodes.insert(EntityNode {
+            entity: entity_idx,
+            next: first_entity,
+        });
+        self.nodes.get_mut(node_data.idx).first_entity = Some(e_node);
+
+        // If the leaf is full, split it.
+        if self.nodes.get(node_data.idx).num_entities == (self.max_entities as usize)
+            && node_data.level < self.max_level
+        {
+            let entities = node::split(&mut self.nodes, &mut self.entity_nodes, node_data.idx, 8);
+
+            // Transfer the entities in the former leaf node to its new children.
+            for i in 0..entities.cursor() {
+                self.node_insert(node_data, *entities.get(i));
+            }
+        } else {
+            self.nodes.get_mut(node_data.idx).num_entities += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sorted_ids, TestVisitor};
+
+    #[test]
+    fn insert_and_traverse() -> Result<(), QuadtreeError> {
+        let mut ot = Octree::new(0.0, 0.0, 0.0, 100.0, 100.0, 100.0, 2)?;
+        let mut tv = TestVisitor::new();
+        ot.insert(-40.0, -40.0, -40.0, -30.0, -30.0, -30.0, ())?;
+        ot.insert(30.0, 30.0, 30.0, 40.0, 40.0, 40.0, ())?;
+        ot.traverse(&mut tv);
+        tv.assert_counts(2, 1, 0);
+        tv.reset();
+
+        // The third entity splits the root into 8 octants, and the one
+        // touching the center goes to the left, top and front side only.
+        ot.insert(-10.0, -10.0, -10.0, 0.0, 0.0, 0.0, ())?;
+        ot.traverse(&mut tv);
+        tv.assert_counts(3, 8, 1);
+        assert_eq!(tv.leaves, vec![1; 8]);
+        tv.reset();
+
+        // Crossing the center links an entity into every octant.
+        ot.insert(-5.0, -5.0, -5.0, 5.0, 5.0, 5.0, ())?;
+        ot.traverse(&mut tv);
+        tv.assert_counts(11, 15, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn insert_error_handling() -> Result<(), QuadtreeError> {
+        let mut ot = Octree::<(), i32>::new(0, 0, 0, 10, 10, 10, 4)?;
+        assert_eq!(
+            ot.insert(1, 1, 2, 2, 2, 1, ()),
+            Err(QuadtreeError::InsertHasInvertedBounds)
+        );
+        assert_eq!(
+            ot.insert(1, 1, 1, 2, 2, 6, ()),
+            Err(QuadtreeError::InsertIsOutOfBounds)
+        );
+        assert!(ot.insert(-5, -5, -5, 5, 5, 5, ()).is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_coordinates() -> Result<(), QuadtreeError> {
+        let flat_root = Octree::<(), f32>::new(0.0, 0.0, 0.0, 10.0, 10.0, 0.0, 4);
+        let nan_root = Octree::<(), f32>::new(0.0, f32::NAN, 0.0, 10.0, 10.0, 10.0, 4);
+        let overflowing_root = Octree::<(), i32>::new(0, 0, i32::MAX, 10, 10, 10, 4);
+        let no_entities = Octree::<(), i32>::new(0, 0, 0, 10, 10, 10, 0);
+        assert!(flat_root.is_err_and(|e| e == QuadtreeError::InvalidRootSize));
+        assert!(nan_root.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(overflowing_root.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        assert!(no_entities.is_err_and(|e| e == QuadtreeError::ZeroMaxEntities));
+
+        let mut ot = Octree::<(), f32>::new(0.0, 0.0, 0.0, 10.0, 10.0, 10.0, 4)?;
+        let nan = ot.insert(0.0, 0.0, f32::NAN, 1.0, 1.0, 1.0, ());
+        let query_nan = ot.query(0.0, 0.0, 0.0, 1.0, 1.0, f32::NAN);
+        assert!(nan.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(query_nan.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+
+        let mut ot = Octree::<(), i32>::new(0, 0, 0, 10, 10, 10, 4)?;
+        let too_deep = ot.insert(0, 0, i32::MIN, 1, 1, i32::MAX, ());
+        let query_too_deep = ot.query(0, 0, i32::MIN, 1, 1, i32::MAX);
+        assert!(too_deep.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        assert!(query_too_deep.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        Ok(())
+    }
+
+    #[test]
+    fn query_and_omit() -> Result<(), QuadtreeError> {
+        let mut ot = Octree::new(0.0, 0.0, 0.0, 100.0, 100.0, 100.0, 2)?;
+        let big = ot.insert(-40.0, -40.0, -40.0, 40.0, 40.0, 40.0, "big")?;
+        let near = ot.insert(-30.0, -30.0, -30.0, -20.0, -20.0, -20.0, "near")?;
+        let far = ot.insert(20.0, 20.0, 45.0, 30.0, 30.0, 48.0, "far")?;
+
+        assert_eq!(sorted_ids(ot.query(-50.0, -50.0, -50.0, 50.0, 50.0, 50.0)?), vec![0, 1, 2]);
+        assert_eq!(sorted_ids(ot.query(-25.0, -25.0, -25.0, -24.0, -24.0, -24.0)?), vec![0, 1]);
+        assert_eq!(sorted_ids(ot.query(21.0, 21.0, 46.0, 22.0, 22.0, 47.0)?), vec![2]);
+        assert!(ot.query(41.0, 41.0, 0.0, 49.0, 49.0, 10.0)?.is_empty());
+
+        let omitted = ot.query_omit(-25.0, -25.0, -25.0, -24.0, -24.0, -24.0, Some(big))?;
+        assert_eq!(omitted, vec![(near, &"near")]);
+        assert_eq!(ot.get(far), Some(&"far"));
+        Ok(())
+    }
+
+    #[test]
+    fn remove_and_cleanup() -> Result<(), QuadtreeError> {
+        let mut ot = Octree::new(0.0, 0.0, 0.0, 100.0, 100.0, 100.0, 2)?;
+        let mut h = Vec::new();
+        for i in 0..8 {
+            let x = if i & 1 == 0 { -40.0 } else { 30.0 };
+            let y = if i & 2 == 0 { -40.0 } else { 30.0 };
+            let z = if i & 4 == 0 { -40.0 } else { 30.0 };
+            h.push(ot.insert(x, y, z, x + 10.0, y + 10.0, z + 10.0, i)?);
+        }
+        let mut tv = TestVisitor::new();
+        ot.traverse(&mut tv);
+        tv.assert_counts(8, 8, 1);
+        tv.reset();
+
+        for (i, entity) in h.iter().enumerate().skip(2) {
+            assert_eq!(ot.remove(*entity), Ok(i));
+        }
+        assert_eq!(ot.remove(h[2]), Err(QuadtreeError::EntityAlreadyRemoved));
+        let unknown = EntityHandle { index: 99, generation: 0 };
+        assert_eq!(ot.remove(unknown), Err(QuadtreeError::EntityNotFound));
+        assert!(!ot.contains(h[2]));
+
+        ot.cleanup();
+        ot.traverse(&mut tv);
+        tv.assert_counts(2, 1, 0);
+        tv.reset();
+        assert_eq!(sorted_ids(ot.query(-50.0, -50.0, -50.0, 50.0, 50.0, 50.0)?), vec![0, 1]);
+
+        // Freed slots are reused with new generations.
+        let reused = ot.insert(0.0, 0.0, 0.0, 1.0, 1.0, 1.0, 9)?;
+        assert_eq!(reused.index, h[7].index);
+        assert_ne!(reused, h[7]);
+        Ok(())
+    }
+}
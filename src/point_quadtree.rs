@@ -0,0 +1,462 @@
+use crate::coordinate::Coordinate;
+use crate::list::List;
+use crate::node::{self, Node};
+use crate::quadtree::{EntityHandle, NodeData, Quadtree, QuadtreeError, Region, Visitor};
+
+/// A stored point. Every point lives in exactly one leaf, so it carries the
+/// link to the next point of that leaf itself instead of needing a separate
+/// entity node.
+#[derive(Copy, Clone, Debug, Default)]
+struct Point<S: Coordinate> {
+    x: S,
+    y: S,
+    next: Option<usize>,
+}
+
+/// A quadtree holding points instead of boxes. Each point is stored once,
+/// in the leaf owning it.
+#[derive(Clone, Debug)]
+pub struct PointQuadtree<T, S: Coordinate = f32> {
+    root: NodeData<S>,
+    max_entities: u16,
+    max_depth: u8,
+    points: List<Point<S>>,
+    payloads: Vec<Option<T>>,
+    nodes: List<Node>,
+}
+
+impl<T, S: Coordinate> PointQuadtree<T, S> {
+    /// Creates a tree centered at `(x, y)`, checked like [`Quadtree::new`].
+    pub fn new(
+        x: S,
+        y: S,
+        width: S,
+        height: S,
+        max_entities_per_region: u16,
+    ) -> Result<Self, QuadtreeError> {
+        let [hx, hy] = node::check_root([x, y], [width, height])?;
+        if max_entities_per_region == 0 {
+            return Err(QuadtreeError::ZeroMaxEntities);
+        }
+        let mut nodes = List::default();
+        let root_idx = nodes.insert(Node::default());
+        Ok(Self {
+            root: NodeData::root(root_idx, x, y, hx, hy),
+            max_entities: max_entities_per_region,
+            max_depth: Quadtree::<T, S>::calc_max_depth(width, height),
+            nodes,
+            points: List::default(),
+            payloads: Vec::new(),
+        })
+    }
+
+    pub fn insert(&mut self, x: S, y: S, payload: T) -> Result<EntityHandle, QuadtreeError> {
+        // NaN fails every comparison, so it would pass the bounds check.
+        if !(x.is_finite() && y.is_finite()) {
+            return Err(QuadtreeError::NonFiniteCoordinate);
+        }
+        let root = &self.root.region;
+        if x < root.left || x > root.right || y < root.top || y > root.bottom {
+            return Err(QuadtreeError::InsertIsOutOfBounds);
+        }
+        let new_point_idx = self.points.insert(Point { x, y, next: None });
+        if new_point_idx == self.payloads.len() {
+            self.payloads.push(Some(payload));
+        } else {
+            self.payloads[new_point_idx] = Some(payload);
+        }
+        let leaf = self.find_leaf(self.root, x, y);
+        self.leaf_insert(leaf, new_point_idx);
+        Ok(self.handle(new_point_idx))
+    }
+
+    /// Returns true if the handle refers to a point that is still in the tree.
+    pub fn contains(&self, entity: EntityHandle) -> bool {
+        node::check_handle(&self.points, entity).is_ok()
+    }
+
+    pub fn get(&self, entity: EntityHandle) -> Option<&T> {
+        match self.contains(entity) {
+            true => self.payloads[entity.index].as_ref(),
+            false => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, entity: EntityHandle) -> Option<&mut T> {
+        match self.contains(entity) {
+            true => self.payloads[entity.index].as_mut(),
+            false => None,
+        }
+    }
+
+    /// Removes a point, returning its payload.
+    pub fn remove(&mut self, entity: EntityHandle) -> Result<T, QuadtreeError> {
+        node::check_handle(&self.points, entity)?;
+        let point_idx = entity.index;
+        let payload = self.payloads[point_idx]
+            .take()
+            .expect("point in the tree has no payload");
+
+        let point = *self.points.get(point_idx);
+        let leaf = self.find_leaf(self.root, point.x, point.y);
+        let mut next_idx = self.nodes.get(leaf.idx).first_entity;
+        let mut prev_idx = None;
+        while let Some(idx) = next_idx {
+            if idx == point_idx {
+                break;
+            }
+            prev_idx = next_idx;
+            next_idx = self.points.get(idx).next;
+        }
+        match prev_idx {
+            None => self.nodes.get_mut(leaf.idx).first_entity = point.next,
+            Some(prev) => self.points.get_mut(prev).next = point.next,
+        }
+        self.nodes.get_mut(leaf.idx).num_entities -= 1;
+        self.points.erase(point_idx);
+        Ok(payload)
+    }
+
+    /// Collapses every branch whose leaves hold no more points than a single
+    /// leaf may. Merges cascade upward, so a single call leaves nothing to
+    /// collapse.
+    pub fn cleanup(&mut self) {
+        // Walking the branches backwards visits children before their parent.
+        let mut branches = node::branches(&self.nodes, self.root.idx, 4);
+        while branches.cursor() > 0 {
+            let node_idx = branches.pop();
+            let fc = self.nodes.get(node_idx).first_child.unwrap();
+            let num_points: usize = (fc..fc + 4).map(|i| self.nodes.get(i).num_entities).sum();
+            if node::has_only_leaves(&self.nodes, node_idx, 4)
+                && num_points <= self.max_entities as usize
+            {
+                self.collapse(node_idx);
+            }
+        }
+    }
+
+    pub fn query(
+        &self,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
+        self.query_omit(x1, y1, x2, y2, None)
+    }
+
+    /// Returns the points inside the box, including those on its edges.
+    /// Points are never stored twice, so no deduplication is needed.
+    pub fn query_omit(
+        &self,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+        omit_entity: Option<EntityHandle>,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
+        if ![x1, y1, x2, y2].into_iter().all(S::is_finite) {
+            return Err(QuadtreeError::NonFiniteCoordinate);
+        }
+        let mut out = Vec::<(EntityHandle, &T)>::new();
+        let query = Region {
+            left: x1,
+            top: y1,
+            right: x2,
+            bottom: y2,
+        };
+        let mut to_process = List::<NodeData<S>>::default();
+        to_process.push(self.root);
+
+        while to_process.cursor() > 0 {
+            let nd_data = to_process.pop();
+            let node = self.nodes.get(nd_data.idx);
+            if let Some(fc) = node.first_child {
+                for quadrant in 0..4 {
+                    let child = nd_data.child(fc, quadrant);
+                    if child.region.intersects(&query) {
+                        to_process.push(child);
+                    }
+                }
+                continue;
+            }
+
+            let mut next_idx = node.first_entity;
+            while let Some(point_idx) = next_idx {
+                let point = self.points.get(point_idx);
+                if point.x >= x1
+                    && point.x <= x2
+                    && point.y >= y1
+                    && point.y <= y2
+                    && omit_entity != Some(self.handle(point_idx))
+                {
+                    out.push((self.handle(point_idx), self.payload(point_idx)));
+                }
+                next_idx = point.next;
+            }
+        }
+        Ok(out)
+    }
+
+    /// Visits the tree like [`Quadtree::traverse`], reporting every point
+    /// as an entity of zero size.
+    pub fn traverse(&self, visitor: &mut impl Visitor<T, S>) {
+        let mut to_process = List::<NodeData<S>>::default();
+        to_process.push(self.root);
+
+        while to_process.cursor() > 0 {
+            let nd_data = to_process.pop();
+            let node = self.nodes.get(nd_data.idx);
+            if let Some(fc) = node.first_child {
+                for quadrant in 0..4 {
+                    to_process.push(nd_data.child(fc, quadrant));
+                }
+                visitor.branch(
+                    nd_data.depth,
+                    nd_data.idx,
+                    fc,
+                    nd_data.x,
+                    nd_data.y,
+                    nd_data.hx + nd_data.hx,
+                    nd_data.hy + nd_data.hy,
+                );
+                continue;
+            }
+
+            visitor.leaf(
+                nd_data.depth,
+                nd_data.idx,
+                node.num_entities,
+                node.first_entity,
+                nd_data.x,
+                nd_data.y,
+                nd_data.hx + nd_data.hx,
+                nd_data.hy + nd_data.hy,
+            );
+            let mut next_idx = node.first_entity;
+            while let Some(point_idx) = next_idx {
+                let point = self.points.get(point_idx);
+                visitor.entity(
+                    self.handle(point_idx),
+                    self.payload(point_idx),
+                    point_idx,
+                    point.next,
+                    point.x,
+                    point.y,
+                    S::default(),
+                    S::default(),
+                );
+                next_idx = point.next;
+            }
+        }
+    }
+
+    fn handle(&self, point_idx: usize) -> EntityHandle {
+        EntityHandle {
+            index: point_idx,
+            generation: self.points.generation(point_idx),
+        }
+    }
+
+    fn payload(&self, point_idx: usize) -> &T {
+        self.payloads[point_idx]
+            .as_ref()
+            .expect("point in the tree has no payload")
+    }
+
+    /// Returns the leaf below `start_node` owning the point.
+    fn find_leaf(&self, start_node: NodeData<S>, x: S, y: S) -> NodeData<S> {
+        let mut nd_data = start_node;
+        while let Some(fc) = self.nodes.get(nd_data.idx).first_child {
+            let quadrant = (x > nd_data.x) as usize | ((y > nd_data.y) as usize) << 1;
+            nd_data = nd_data.child(fc, quadrant);
+            debug_assert!(nd_data.region.owns(&self.root.region, x, y));
+        }
+        nd_data
+    }
+
+    fn leaf_insert(&mut self, node_data: NodeData<S>, point_idx: usize) {
+        let node = *self.nodes.get(node_data.idx);
+        if node.num_entities < self.max_entities as usize || node_data.depth >= self.max_depth {
+            self.points.get_mut(point_idx).next = node.first_entity;
+            let node = self.nodes.get_mut(node_data.idx);
+            node.first_entity = Some(point_idx);
+            node.num_entities += 1;
+            return;
+        }
+
+        // The leaf is full, so split it and hand its points to the children.
+        let fc = self.nodes.insert(Node::default());
+        for _ in 1..4 {
+            self.nodes.insert(Node::default());
+        }
+        *self.nodes.get_mut(node_data.idx) = Node {
+            first_child: Some(fc),
+            ..Node::default()
+        };
+        let mut next_idx = node.first_entity;
+        while let Some(idx) = next_idx {
+            next_idx = self.points.get(idx).next;
+            let point = self.points.get(idx);
+            let leaf = self.find_leaf(node_data, point.x, point.y);
+            self.leaf_insert(leaf, idx);
+        }
+        let point = self.points.get(point_idx);
+        let leaf = self.find_leaf(node_data, point.x, point.y);
+        self.leaf_insert(leaf, point_idx);
+    }
+
+    /// Turns the branch `node_idx` back into a leaf holding the points of
+    /// its 4 leaf children.
+    fn collapse(&mut self, node_idx: usize) {
+        let fc = self.nodes.get(node_idx).first_child.unwrap();
+        let mut first_entity = None;
+        let mut num_entities = 0;
+        for child_idx in fc..fc + 4 {
+            let mut next_idx = self.nodes.get(child_idx).first_entity;
+            while let Some(idx) = next_idx {
+                next_idx = self.points.get(idx).next;
+                self.points.get_mut(idx).next = first_entity;
+                first_entity = Some(idx);
+                num_entities += 1;
+            }
+        }
+
+        // Remove the children in reverse order so that they can be
+        // reclaimed on subsequent insertions in proper order.
+        for child_idx in (fc..fc + 4).rev() {
+            self.nodes.erase(child_idx);
+        }
+        *self.nodes.get_mut(node_idx) = Node {
+            first_child: None,
+            first_entity,
+            num_entities,
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{sorted_ids, TestVisitor};
+
+    #[test]
+    fn points_on_split_lines() -> Result<(), QuadtreeError> {
+        //   -50       0       50
+        //    +-------+-------+
+        //    |   a   |       |
+        //    |       |       |
+        //    +-------c-------+ 0
+        //    |       |       |
+        //    |   b   d       |
+        //    +-------+-------+
+        let mut pqt = PointQuadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        let mut tv = TestVisitor::new();
+        pqt.insert(-25.0, -25.0, 'a')?;
+        pqt.insert(-25.0, 25.0, 'b')?;
+        pqt.insert(0.0, 0.0, 'c')?;
+        pqt.insert(0.0, 25.0, 'd')?;
+
+        // Every point is in exactly one leaf, and points on a split line go
+        // to the left or top side.
+        pqt.traverse(&mut tv);
+        assert_eq!(tv.entities.len(), 4);
+        assert_eq!(tv.branches, vec![0]);
+        assert_eq!(tv.leaf_entities.iter().sum::<usize>(), 4);
+        assert_eq!(sorted_ids(pqt.query(-50.0, -50.0, 0.0, 0.0)?), vec![0, 2]);
+        assert_eq!(sorted_ids(pqt.query(-50.0, 0.0, 0.0, 50.0)?), vec![1, 2, 3]);
+        assert_eq!(sorted_ids(pqt.query(1.0, 0.0, 50.0, 50.0)?), Vec::<usize>::new());
+        tv.reset();
+
+        // Points on the outer edges are inside the tree.
+        pqt.insert(50.0, 50.0, 'e')?;
+        assert_eq!(
+            pqt.insert(50.1, 0.0, 'f'),
+            Err(QuadtreeError::InsertIsOutOfBounds)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_coordinates() -> Result<(), QuadtreeError> {
+        let flat_root = PointQuadtree::<(), f32>::new(0.0, 0.0, 10.0, 0.0, 4);
+        let nan_root = PointQuadtree::<(), f32>::new(f32::NAN, 0.0, 10.0, 10.0, 4);
+        let overflowing_root = PointQuadtree::<(), i32>::new(i32::MAX, 0, 10, 10, 4);
+        let no_entities = PointQuadtree::<(), i32>::new(0, 0, 10, 10, 0);
+        assert!(flat_root.is_err_and(|e| e == QuadtreeError::InvalidRootSize));
+        assert!(nan_root.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(overflowing_root.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        assert!(no_entities.is_err_and(|e| e == QuadtreeError::ZeroMaxEntities));
+
+        // NaN used to slip past the bounds check and land in a leaf that
+        // does not own it once the root had split.
+        let mut pqt = PointQuadtree::new(0.0, 0.0, 100.0, 100.0, 1)?;
+        pqt.insert(-25.0, -25.0, ())?;
+        pqt.insert(25.0, 25.0, ())?;
+        let nan = pqt.insert(f32::NAN, 10.0, ());
+        let infinite = pqt.insert(10.0, f32::INFINITY, ());
+        let query_nan = pqt.query(0.0, 0.0, f32::NAN, 10.0);
+        assert!(nan.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(infinite.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(query_nan.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert_eq!(pqt.query(-50.0, -50.0, 50.0, 50.0)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn query_omit_and_payloads() -> Result<(), QuadtreeError> {
+        let mut pqt = PointQuadtree::<u32, i32>::new(0, 0, 64, 64, 1)?;
+        let mut handles = Vec::new();
+        for i in 0..16 {
+            handles.push(pqt.insert(-30 + i * 4, -30 + i * 4, i as u32)?);
+        }
+        assert_eq!(pqt.query(-32, -32, 32, 32)?.len(), 16);
+        assert_eq!(sorted_ids(pqt.query(-2, -2, 6, 6)?), vec![7, 8, 9]);
+        assert_eq!(sorted_ids(pqt.query_omit(-2, -2, 6, 6, Some(handles[8]))?), vec![7, 9]);
+
+        *pqt.get_mut(handles[3]).unwrap() += 100;
+        assert_eq!(pqt.get(handles[3]), Some(&103));
+        Ok(())
+    }
+
+    #[test]
+    fn coincident_points_stop_at_max_depth() -> Result<(), QuadtreeError> {
+        let mut pqt = PointQuadtree::<(), i32>::new(0, 0, 16, 16, 1)?;
+        for _ in 0..5 {
+            pqt.insert(3, 3, ())?;
+        }
+        let mut tv = TestVisitor::new();
+        pqt.traverse(&mut tv);
+        assert_eq!(tv.entities.len(), 5);
+        assert_eq!(tv.branches.iter().max(), Some(&2));
+        assert_eq!(sorted_ids(pqt.query(3, 3, 3, 3)?), vec![0, 1, 2, 3, 4]);
+        Ok(())
+    }
+
+    #[test]
+    fn remove_and_cleanup() -> Result<(), QuadtreeError> {
+        let mut pqt = PointQuadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        let mut handles = Vec::new();
+        for i in 0..12 {
+            let x = -45.0 + 7.5 * i as f32;
+            handles.push(pqt.insert(x, x * 0.5, i)?);
+        }
+        let mut tv = TestVisitor::new();
+        pqt.traverse(&mut tv);
+        assert!(!tv.branches.is_empty());
+        tv.reset();
+
+        for (i, handle) in handles.iter().enumerate().skip(2) {
+            assert_eq!(pqt.remove(*handle), Ok(i));
+        }
+        assert_eq!(pqt.remove(handles[2]), Err(QuadtreeError::EntityAlreadyRemoved));
+        assert!(!pqt.contains(handles[2]));
+
+        pqt.cleanup();
+        pqt.traverse(&mut tv);
+        assert_eq!(tv.branches, Vec::<u8>::new());
+        assert_eq!(tv.leaf_entities, vec![2]);
+        assert_eq!(sorted_ids(pqt.query(-50.0, -50.0, 50.0, 50.0)?), vec![0, 1]);
+        Ok(())
+    }
+}
@@ -1,37 +1,45 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::ops::ControlFlow;
 use thiserror::Error;
+use crate::builder::QuadtreeBuilder;
+use crate::coordinate::Coordinate;
 use crate::list::List;
+use crate::node::{self, EntityNode, Node};
 
-pub trait Visitor {
+#[allow(clippy::too_many_arguments)]
+pub trait Visitor<T, S: Coordinate> {
     fn entity(
         &mut self,
-        entity_id: usize,
+        entity: EntityHandle,
+        payload: &T,
         idx: usize,
         next_entity: Option<usize>,
-        x: i32,
-        y: i32,
-        width: i32,
-        height: i32,
+        x: S,
+        y: S,
+        width: S,
+        height: S,
     );
     fn leaf(
         &mut self,
         depth: u8,
         idx: usize,
-        num_children: Option<usize>,
+        num_entities: usize,
         first_entity: Option<usize>,
-        x: i32,
-        y: i32,
-        width: i32,
-        height: i32,
+        x: S,
+        y: S,
+        width: S,
+        height: S,
     );
     fn branch(
         &mut self,
         depth: u8,
         idx: usize,
         first_leaf: usize,
-        x: i32,
-        y: i32,
-        width: i32,
-        height: i32,
+        x: S,
+        y: S,
+        width: S,
+        height: S,
     );
 }
 
@@ -41,151 +49,827 @@ pub enum QuadtreeError {
     InsertHasInvertedBounds,
     #[error("entity box is outside of the quadtree bounds")]
     InsertIsOutOfBounds,
+    #[error("entity handle does not refer to an entity in the quadtree")]
+    EntityNotFound,
+    #[error("entity has already been removed from the quadtree")]
+    EntityAlreadyRemoved,
+    #[error("max_entities must be at least 1")]
+    ZeroMaxEntities,
+    #[error("min_cell_size must be a number of at least 0")]
+    InvalidMinCellSize,
+    #[error("looseness must be a finite number of at least 1")]
+    InvalidLooseness,
+    #[error("wrapping requires split storage")]
+    WrapRequiresSplitStorage,
+    #[error("a wrapping quadtree cannot grow")]
+    WrapWithAutoGrow,
+    #[error("wrapping can only be changed on an empty quadtree")]
+    WrapOnNonEmptyTree,
+    #[error("coordinates must not be NaN or infinite")]
+    NonFiniteCoordinate,
+    #[error("coordinates are too large for the coordinate type")]
+    CoordinateOverflow,
+    #[error("the quadtree must have a positive width and height")]
+    InvalidRootSize,
+    #[error("radius must be a number of at least 0")]
+    NegativeRadius,
 }
 
-#[derive(Copy, Clone, Debug)]
-struct EntityNode {
-    next: Option<usize>,
-    entity: usize,
+/// A broken invariant found by [`Quadtree::validate`]. Nodes and entity
+/// nodes are named by their index in the internal lists.
+#[derive(Error, Debug, PartialEq)]
+pub enum IntegrityError {
+    #[error("node {node} is reachable but vacant")]
+    VacantNode { node: usize },
+    #[error("node {node} is reachable along more than one path")]
+    NodeReachedTwice { node: usize },
+    #[error("node {node} is occupied but not reachable from the root")]
+    UnreachableNode { node: usize },
+    #[error("branch {node} holds entities although storage is split")]
+    BranchHoldsEntities { node: usize },
+    #[error("node {node} links the vacant entity node {entity_node}")]
+    VacantEntityNode { node: usize, entity_node: usize },
+    #[error("entity node {entity_node} is linked more than once")]
+    EntityNodeReachedTwice { entity_node: usize },
+    #[error("entity node {entity_node} is occupied but not linked into any node")]
+    UnreachableEntityNode { entity_node: usize },
+    #[error("node {node} links the vacant entity {entity}")]
+    VacantEntity { node: usize, entity: usize },
+    #[error("node {node} counts {counted} entities but links {linked}")]
+    EntityCountMismatch {
+        node: usize,
+        counted: usize,
+        linked: usize,
+    },
+    #[error("entity {entity} and its payload disagree on whether it is in the tree")]
+    PayloadMismatch { entity: usize },
+    #[error("entity {entity} is linked into node {node} more than once")]
+    DuplicateLink { entity: usize, node: usize },
+    #[error("entity {entity} belongs in node {node} but is not linked there")]
+    EntityNotLinked { entity: usize, node: usize },
+    #[error("entity {entity} is linked into node {node} where it does not belong")]
+    EntityWronglyLinked { entity: usize, node: usize },
 }
 
-impl Default for EntityNode {
-    fn default() -> Self {
-        Self {
-            next: None,
-            entity: 0,
+/// A reference to an entity stored in a [`Quadtree`].
+///
+/// Entity slots are reused after removal, so the handle also records the
+/// generation of its slot. A handle kept around after its entity has been
+/// removed is stale and will no longer match anything in the tree.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct EntityHandle {
+    pub index: usize,
+    pub generation: u32,
+}
+
+/// The box of an entity, as passed to [`Quadtree::insert`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Rect<S: Coordinate = f32> {
+    pub x1: S,
+    pub y1: S,
+    pub x2: S,
+    pub y2: S,
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+struct Entity<S: Coordinate> {
+    left: S,
+    top: S,
+    right: S,
+    bottom: S,
+}
+
+impl<S: Coordinate> Entity<S> {
+    fn region(&self) -> Region<S> {
+        Region {
+            left: self.left,
+            top: self.top,
+            right: self.right,
+            bottom: self.bottom,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-struct Entity {
-    left: i32,
-    top: i32,
-    right: i32,
-    bottom: i32,
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct NodeData<S: Coordinate> {
+    pub(crate) idx: usize,
+    pub(crate) depth: u8,
+    pub(crate) x: S,
+    pub(crate) y: S,
+    pub(crate) hx: S,
+    pub(crate) hy: S,
+    pub(crate) region: Region<S>,
 }
 
-impl Default for Entity {
-    fn default() -> Self {
+impl<S: Coordinate> NodeData<S> {
+    pub(crate) fn root(idx: usize, x: S, y: S, hx: S, hy: S) -> Self {
+        Self {
+            idx,
+            depth: 0,
+            x,
+            y,
+            hx,
+            hy,
+            region: Region {
+                left: x - hx,
+                top: y - hy,
+                right: x + hx,
+                bottom: y + hy,
+            },
+        }
+    }
+
+    /// Returns the data of one of the four children of this node. Quadrants
+    /// are numbered left to right, top to bottom, starting at `first_child`.
+    pub(crate) fn child(&self, first_child: usize, quadrant: usize) -> Self {
+        let qx = self.hx.half();
+        let qy = self.hy.half();
         Self {
-            left: 0,
-            top: 0,
-            right: 0,
-            bottom: 0,
+            idx: first_child + quadrant,
+            depth: self.depth + 1,
+            x: match quadrant & 1 {
+                0 => self.x - qx,
+                _ => self.x + qx,
+            },
+            y: match quadrant & 2 {
+                0 => self.y - qy,
+                _ => self.y + qy,
+            },
+            hx: qx,
+            hy: qy,
+            region: self.region.split(self.x, self.y, quadrant),
         }
     }
 }
 
-#[derive(Copy, Clone, Debug)]
-struct Node {
-    first_child: Option<usize>,
-    num_children: Option<usize>,
+/// How entities are assigned to the nodes of a [`Quadtree`].
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Storage {
+    /// Entities are linked into every leaf their box overlaps.
+    Split,
+    /// The bounds of every node are scaled by the given factor around its
+    /// center, and each entity is stored once, in the deepest node along
+    /// its center whose loose bounds contain the whole box. Large entities
+    /// stay near the root instead of being linked into many leaves.
+    Loose(f64),
+    /// Each entity is stored once, in the deepest node whose area fully
+    /// contains it, as in an MX-CIF quadtree. Entities crossing a split
+    /// line stay on the branch above it.
+    Enclosing,
 }
 
-impl Default for Node {
-    fn default() -> Self {
+/// The area a node is responsible for. With integer coordinates halving
+/// truncates, so the children's center and half size can fall short of
+/// the parent's edges. Regions are instead split at the parent's center
+/// and keep the outer edges exact.
+#[derive(Copy, Clone, Debug, Default)]
+pub(crate) struct Region<S: Coordinate> {
+    pub(crate) left: S,
+    pub(crate) top: S,
+    pub(crate) right: S,
+    pub(crate) bottom: S,
+}
+
+impl<S: Coordinate> Region<S> {
+    /// Returns one quadrant of the region split at `(x, y)`.
+    fn split(&self, x: S, y: S, quadrant: usize) -> Self {
+        let (left, right) = match quadrant & 1 {
+            0 => (self.left, x),
+            _ => (x, self.right),
+        };
+        let (top, bottom) = match quadrant & 2 {
+            0 => (self.top, y),
+            _ => (y, self.bottom),
+        };
         Self {
-            first_child: None,
-            num_children: Some(0),
+            left,
+            top,
+            right,
+            bottom,
         }
     }
+
+    /// Returns true if a point inside the tree is routed into this region.
+    /// Like boxes, points on an edge shared with a sibling belong to the
+    /// left or top side, so every point is owned by exactly one leaf.
+    pub(crate) fn owns(&self, root: &Region<S>, x: S, y: S) -> bool {
+        (x > self.left || self.left == root.left)
+            && x <= self.right
+            && (y > self.top || self.top == root.top)
+            && y <= self.bottom
+    }
+
+    /// Returns the distance along the ray at which it enters the region, if
+    /// it does so within `max_distance`. The direction must be normalized.
+    fn ray_entry(&self, ox: f64, oy: f64, dx: f64, dy: f64, max_distance: f64) -> Option<f64> {
+        let mut t_min = 0.0f64;
+        let mut t_max = max_distance;
+        let slabs = [
+            (ox, dx, self.left.to_f64(), self.right.to_f64()),
+            (oy, dy, self.top.to_f64(), self.bottom.to_f64()),
+        ];
+        for (o, d, lo, hi) in slabs {
+            if d == 0.0 {
+                // Parallel to the slab, so the ray is either always or
+                // never between its edges.
+                if o < lo || o > hi {
+                    return None;
+                }
+            } else {
+                let t1 = (lo - o) / d;
+                let t2 = (hi - o) / d;
+                t_min = t_min.max(t1.min(t2));
+                t_max = t_max.min(t1.max(t2));
+            }
+        }
+        match t_min <= t_max {
+            true => Some(t_min),
+            false => None,
+        }
+    }
+
+    fn to_f64(self) -> Region<f64> {
+        Region {
+            left: self.left.to_f64(),
+            top: self.top.to_f64(),
+            right: self.right.to_f64(),
+            bottom: self.bottom.to_f64(),
+        }
+    }
+
+    /// Returns the region scaled by `factor` around its center.
+    fn loosen(&self, factor: f64) -> Region<f64> {
+        let region = self.to_f64();
+        let hx = (region.right - region.left) * 0.5;
+        let hy = (region.bottom - region.top) * 0.5;
+        let pad_x = hx * (factor - 1.0);
+        let pad_y = hy * (factor - 1.0);
+        Region {
+            left: region.left - pad_x,
+            top: region.top - pad_y,
+            right: region.right + pad_x,
+            bottom: region.bottom + pad_y,
+        }
+    }
+
+    /// Returns true if a box inside the tree is routed into this region.
+    /// Children share the parent's center as an edge, and a box touching
+    /// that edge only goes to the children on its left or top side.
+    fn receives(&self, root: &Region<S>, other: &Region<S>) -> bool {
+        other.left <= self.right
+            && (other.right > self.left || self.left == root.left)
+            && other.top <= self.bottom
+            && (other.bottom > self.top || self.top == root.top)
+    }
+
+    fn contains(&self, other: &Region<S>) -> bool {
+        other.left >= self.left
+            && other.top >= self.top
+            && other.right <= self.right
+            && other.bottom <= self.bottom
+    }
+
+    pub(crate) fn intersects(&self, other: &Region<S>) -> bool {
+        other.left <= self.right
+            && other.right >= self.left
+            && other.top <= self.bottom
+            && other.bottom >= self.top
+    }
+
+    /// Squared distance from a point to the closest point of the region.
+    fn distance_sq(&self, px: f64, py: f64) -> f64 {
+        let dx = (self.left.to_f64() - px).max(px - self.right.to_f64()).max(0.0);
+        let dy = (self.top.to_f64() - py).max(py - self.bottom.to_f64()).max(0.0);
+        dx * dx + dy * dy
+    }
+}
+
+/// The parts of a box inside the root. Without wrapping this is the box
+/// itself, otherwise a box crossing the edges of the root is cut into up
+/// to 4 parts, each moved onto the opposite side.
+#[derive(Copy, Clone, Debug, Default)]
+struct Pieces<S: Coordinate> {
+    regions: [Region<S>; 4],
+    len: usize,
+}
+
+impl<S: Coordinate> Pieces<S> {
+    fn iter(&self) -> impl Iterator<Item = &Region<S>> {
+        self.regions[..self.len].iter()
+    }
 }
 
 #[derive(Copy, Clone, Debug)]
-struct NodeData {
+enum Candidate<S: Coordinate> {
+    Node(NodeData<S>),
+    Entity(usize),
+}
+
+/// A step of the pair search of trees that store every entity once.
+#[derive(Copy, Clone, Debug)]
+enum PairTask<S: Coordinate> {
+    /// Every pair within the subtree of the node.
+    Within(NodeData<S>),
+    /// The entities of the first node against the subtree of the second.
+    Across(NodeData<S>, NodeData<S>),
+    /// Two subtrees that share no nodes.
+    Between(NodeData<S>, NodeData<S>),
+}
+
+/// A search candidate ordered so that a [`BinaryHeap`] pops the closest first.
+#[derive(Copy, Clone, Debug)]
+struct Ranked<S: Coordinate> {
+    distance: f64,
+    candidate: Candidate<S>,
+}
+
+impl<S: Coordinate> PartialEq for Ranked<S> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<S: Coordinate> Eq for Ranked<S> {}
+
+impl<S: Coordinate> PartialOrd for Ranked<S> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<S: Coordinate> Ord for Ranked<S> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.distance.total_cmp(&self.distance)
+    }
+}
+
+/// A branch on the path of an unfinished cleanup pass. The generation
+/// tells whether the node slot was reused since the frame was pushed.
+#[derive(Copy, Clone, Debug)]
+struct CleanupFrame {
     idx: usize,
-    depth: u8,
-    x: i32,
-    y: i32,
-    hx: i32,
-    hy: i32,
+    generation: u32,
+    next_quadrant: usize,
 }
 
-impl Default for NodeData {
-    fn default() -> Self {
+/// Scratch space for queries that is kept by the caller between calls.
+///
+/// Entities already reported by a query are tracked with a stamp that is
+/// bumped per query, so the dedupe buffer never has to be cleared, and the
+/// leaf buffers and search heap keep their capacity from one query to the
+/// next. A new context allocates nothing until it is first used.
+#[derive(Clone, Debug)]
+pub struct QueryContext<S: Coordinate = f32> {
+    stamp: u32,
+    seen: Vec<u32>,
+    leaves: List<NodeData<S>>,
+    to_process: List<NodeData<S>>,
+    heap: BinaryHeap<Ranked<S>>,
+}
+
+impl<S: Coordinate> QueryContext<S> {
+    pub fn new() -> Self {
         Self {
-            idx: 0,
-            depth: 0,
-            x: 0,
-            y: 0,
-            hx: 0,
-            hy: 0,
+            stamp: 0,
+            seen: Vec::new(),
+            leaves: List::new(0),
+            to_process: List::new(0),
+            heap: BinaryHeap::new(),
+        }
+    }
+
+    /// Starts a new query over a tree with `num_entities` entity slots.
+    fn begin(&mut self, num_entities: usize) {
+        self.stamp = self.stamp.wrapping_add(1);
+        if self.stamp == 0 {
+            // The stamp wrapped, so old marks could collide with new ones.
+            self.seen.fill(0);
+            self.stamp = 1;
         }
+        if self.seen.len() < num_entities {
+            self.seen.resize(num_entities, 0);
+        }
+    }
+
+    fn is_seen(&self, entity_idx: usize) -> bool {
+        self.seen[entity_idx] == self.stamp
+    }
+
+    fn mark_seen(&mut self, entity_idx: usize) {
+        self.seen[entity_idx] = self.stamp;
+    }
+}
+
+impl<S: Coordinate> Default for QueryContext<S> {
+    fn default() -> Self {
+        QueryContext::new()
     }
 }
 
+/// A summary of the shape and occupancy of a [`Quadtree`], see
+/// [`Quadtree::stats`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuadtreeStats {
+    pub nodes: usize,
+    pub leaves: usize,
+    pub branches: usize,
+    /// The number of leaves at each depth, starting with the root.
+    pub depth_histogram: Vec<usize>,
+    pub max_entities_per_leaf: usize,
+    /// Entities held by branches, as happens without split storage, are
+    /// not counted towards any leaf.
+    pub mean_entities_per_leaf: f64,
+    pub entities: usize,
+    /// The number of links between entities and nodes.
+    pub entity_nodes: usize,
+    /// Links per entity. Entities only have more than one link when they
+    /// are split across leaves, so this is 1 at best and 0 without entities.
+    pub duplication_factor: f64,
+    /// Erased slots waiting to be reused in each internal list.
+    pub free_nodes: usize,
+    pub free_entity_nodes: usize,
+    pub free_entities: usize,
+}
+
 #[derive(Clone, Debug)]
-pub struct Quadtree {
-    root: NodeData,
+pub struct Quadtree<T, S: Coordinate = f32> {
+    root: NodeData<S>,
     max_entities: u16,
     max_depth: u8,
+    depth_limit: u8,
+    min_cell_size: S,
+    storage: Storage,
+    auto_grow: bool,
+    wrap: bool,
     entity_nodes: List<EntityNode>,
-    entities: List<Entity>,
+    entities: List<Entity<S>>,
+    payloads: Vec<Option<T>>,
     nodes: List<Node>,
+    cleanup_stack: Vec<CleanupFrame>,
 }
 
-impl Quadtree {
-    pub fn new(x: f32, y: f32, width: f32, height: f32, max_entities_per_region: u16) -> Self {
-        let mut nodes = List::default();
+impl<T, S: Coordinate> Quadtree<T, S> {
+    /// Creates a tree centered at `(x, y)`. Fails if the root is not made
+    /// of finite numbers, is empty, or has edges that do not fit the
+    /// coordinate type, or if `max_entities_per_region` is 0.
+    pub fn new(
+        x: S,
+        y: S,
+        width: S,
+        height: S,
+        max_entities_per_region: u16,
+    ) -> Result<Self, QuadtreeError> {
+        Self::with_storage(x, y, width, height, max_entities_per_region, Storage::Split)
+    }
+
+    /// Creates a tree that assigns entities to nodes as set by `storage`.
+    /// Besides the checks of [`Quadtree::new`], a [`Storage::Loose`] factor
+    /// must be a finite number of at least 1, since smaller bounds could
+    /// not hold every entity.
+    pub fn with_storage(
+        x: S,
+        y: S,
+        width: S,
+        height: S,
+        max_entities_per_region: u16,
+        storage: Storage,
+    ) -> Result<Self, QuadtreeError> {
+        QuadtreeBuilder::new(x, y, width, height)
+            .max_entities(max_entities_per_region)
+            .storage(storage)
+            .build()
+    }
+
+    /// Creates the tree configured by `builder` without checking it.
+    pub(crate) fn from_builder(builder: &QuadtreeBuilder<S>) -> Self {
+        let mut nodes = List::new(builder.node_capacity);
         let root_idx = nodes.insert(Node::default());
-        let width = width as i32;
-        let height = height as i32;
+        let (width, height) = (builder.width, builder.height);
         Self {
-            root: NodeData {
-                idx: root_idx,
-                depth: 0,
-                x: x as i32,
-                y: y as i32,
-                hx: width / 2,
-                hy: height / 2,
-            },
-            max_entities: max_entities_per_region,
-            max_depth: Self::calc_max_depth(width, height),
+            root: NodeData::root(root_idx, builder.x, builder.y, width.half(), height.half()),
+            max_entities: builder.max_entities,
+            max_depth: Self::limit_depth(width, height, builder.min_cell_size, builder.max_depth),
+            depth_limit: builder.max_depth,
+            min_cell_size: builder.min_cell_size,
+            storage: builder.storage,
+            auto_grow: builder.auto_grow,
+            wrap: builder.wrap,
             nodes,
-            entity_nodes: List::default(),
-            entities: List::default(),
+            entity_nodes: List::new(builder.entity_node_capacity),
+            entities: List::new(builder.entity_capacity),
+            payloads: Vec::with_capacity(builder.entity_capacity),
+            cleanup_stack: Vec::new(),
         }
     }
 
-    fn calc_max_depth(w: i32, h: i32) -> u8 {
+    /// Lets the root grow to fit boxes inserted or moved outside of it,
+    /// instead of rejecting them with [`QuadtreeError::InsertIsOutOfBounds`].
+    /// Fails with [`QuadtreeError::WrapWithAutoGrow`] on a wrapping tree,
+    /// which never grows.
+    pub fn set_auto_grow(&mut self, enabled: bool) -> Result<(), QuadtreeError> {
+        if enabled && self.wrap {
+            return Err(QuadtreeError::WrapWithAutoGrow);
+        }
+        self.auto_grow = enabled;
+        Ok(())
+    }
+
+    /// Makes the tree wrap around its edges like a torus. Boxes crossing
+    /// the edges of the root are cut into parts that continue on the
+    /// opposite side, queries wrap the same way and distances are measured
+    /// the short way around. A wrapping tree never grows.
+    ///
+    /// Fails if the tree holds entities, which were placed without
+    /// wrapping, and otherwise like [`QuadtreeBuilder::build`] if it does
+    /// not use [`Storage::Split`] or is allowed to grow.
+    pub fn set_wrap(&mut self, enabled: bool) -> Result<(), QuadtreeError> {
+        if self.payloads.iter().any(Option::is_some) {
+            return Err(QuadtreeError::WrapOnNonEmptyTree);
+        }
+        if enabled && self.storage != Storage::Split {
+            return Err(QuadtreeError::WrapRequiresSplitStorage);
+        }
+        if enabled && self.auto_grow {
+            return Err(QuadtreeError::WrapWithAutoGrow);
+        }
+        self.wrap = enabled;
+        Ok(())
+    }
+
+    pub(crate) fn calc_max_depth(w: S, h: S) -> u8 {
+        Self::limit_depth(w, h, S::MIN_CELL_SIZE, S::MAX_DEPTH)
+    }
+
+    /// Returns how deep a root of the given size can be split before its
+    /// cells are no larger than `min_cell_size`, but at most `depth_limit`.
+    fn limit_depth(w: S, h: S, min_cell_size: S, depth_limit: u8) -> u8 {
         let mut depth: u8 = 0;
         let mut size = match w <= h {
             true => w,
             false => h,
         };
-        while size > 2 {
-            size = size / 2;
+        while size > min_cell_size && depth < depth_limit {
+            size = size.half();
             depth += 1;
         }
         depth
     }
 
-    pub fn insert(&mut self, x1: f32, y1: f32, x2: f32, y2: f32) -> Result<usize, QuadtreeError> {
-        if x1 >= x2 || y1 >= y2 {
-            return Err(QuadtreeError::InsertHasInvertedBounds)
-        }
-        let x1 = x1 as i32;
-        let y1 = y1 as i32;
-        let x2 = x2 as i32;
-        let y2 = y2 as i32;
-        if x1 < self.root.x - self.root.hx || x2 > self.root.x + self.root.hx ||
-            y1 < self.root.y - self.root.hy || y2 > self.root.y + self.root.hy {
-            return Err(QuadtreeError::InsertIsOutOfBounds)
-        }
+    pub fn insert(
+        &mut self,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+        payload: T,
+    ) -> Result<EntityHandle, QuadtreeError> {
+        self.fit_bounds(x1, y1, x2, y2)?;
         let new_entity_idx = self.entities.insert(Entity {
             left: x1,
             top: y1,
             right: x2,
             bottom: y2,
         });
+        // Payloads are indexed by entity id, so grow the storage only when
+        // the entity list handed out a brand new slot.
+        if new_entity_idx == self.payloads.len() {
+            self.payloads.push(Some(payload));
+        } else {
+            self.payloads[new_entity_idx] = Some(payload);
+        }
         self.node_insert(self.root, new_entity_idx);
-        Ok(new_entity_idx)
+        Ok(self.handle(new_entity_idx))
     }
 
-    pub fn remove(&mut self, entity_idx: usize) {
+    /// Builds a tree holding every `(x1, y1, x2, y2, payload)` box at once,
+    /// see [`Quadtree::extend`]. The handles are returned in input order.
+    pub fn from_iter(
+        x: S,
+        y: S,
+        width: S,
+        height: S,
+        max_entities_per_region: u16,
+        rects: impl IntoIterator<Item = (S, S, S, S, T)>,
+    ) -> Result<(Self, Vec<EntityHandle>), QuadtreeError> {
+        let mut qt = Self::new(x, y, width, height, max_entities_per_region)?;
+        let handles = qt.extend(rects)?;
+        Ok((qt, handles))
+    }
+
+    /// Inserts many `(x1, y1, x2, y2, payload)` boxes at once and returns
+    /// their handles in input order.
+    ///
+    /// Rather than splitting leaves again and again as entities trickle in,
+    /// the boxes are handed down the tree in batches, so every node is
+    /// split at most once and each entity is routed from the root in a
+    /// single pass. Entities are stored in Morton order of their centers,
+    /// so boxes close in the tree are close in memory as well. Nothing is
+    /// changed if any of the boxes is rejected, and a growing root grows
+    /// once to fit all of them.
+    pub fn extend(
+        &mut self,
+        rects: impl IntoIterator<Item = (S, S, S, S, T)>,
+    ) -> Result<Vec<EntityHandle>, QuadtreeError> {
+        let mut rects: Vec<(S, S, S, S, Option<T>)> = rects
+            .into_iter()
+            .map(|(x1, y1, x2, y2, payload)| (x1, y1, x2, y2, Some(payload)))
+            .collect();
+        let Some(&(x1, y1, x2, y2, _)) = rects.first() else {
+            return Ok(Vec::new());
+        };
+        let mut union = Region {
+            left: x1,
+            top: y1,
+            right: x2,
+            bottom: y2,
+        };
+        for &(x1, y1, x2, y2, _) in &rects {
+            self.check_coordinates(x1, y1, x2, y2)?;
+            if x1 >= x2 || y1 >= y2 {
+                return Err(QuadtreeError::InsertHasInvertedBounds);
+            }
+            union = Region {
+                left: if x1 < union.left { x1 } else { union.left },
+                top: if y1 < union.top { y1 } else { union.top },
+                right: if x2 > union.right { x2 } else { union.right },
+                bottom: if y2 > union.bottom { y2 } else { union.bottom },
+            };
+        }
+        if self.auto_grow && !self.wrap {
+            self.grow_to(&union)?;
+        }
+        for &(x1, y1, x2, y2, _) in &rects {
+            self.check_bounds(x1, y1, x2, y2)?;
+        }
+
+        let mut order: Vec<usize> = (0..rects.len()).collect();
+        order.sort_by_cached_key(|&i| {
+            let (x1, y1, x2, y2, _) = rects[i];
+            self.morton_key(x1, y1, x2, y2)
+        });
+        let mut batch = Vec::with_capacity(rects.len());
+        let mut handles = vec![EntityHandle { index: 0, generation: 0 }; rects.len()];
+        for i in order {
+            let (x1, y1, x2, y2, ref mut payload) = rects[i];
+            let new_entity_idx = self.entities.insert(Entity {
+                left: x1,
+                top: y1,
+                right: x2,
+                bottom: y2,
+            });
+            if new_entity_idx == self.payloads.len() {
+                self.payloads.push(payload.take());
+            } else {
+                self.payloads[new_entity_idx] = payload.take();
+            }
+            batch.push(new_entity_idx);
+            handles[i] = self.handle(new_entity_idx);
+        }
+
+        let mut to_process = vec![(self.root, batch)];
+        while let Some((nd_data, mut batch)) = to_process.pop() {
+            let node = *self.nodes.get(nd_data.idx);
+            if node.first_child.is_none() {
+                if node.num_entities + batch.len() <= self.max_entities as usize
+                    || nd_data.depth >= self.max_depth
+                {
+                    for entity_idx in batch {
+                        self.link(nd_data.idx, entity_idx);
+                    }
+                    continue;
+                }
+
+                // The leaf overflows, so split it and hand down the entities
+                // it held along with the new ones.
+                let held = node::split(&mut self.nodes, &mut self.entity_nodes, nd_data.idx, 4);
+                for i in 0..held.cursor() {
+                    batch.push(*held.get(i));
+                }
+            }
+
+            // Sort the batch into the children, keeping the entities that
+            // fit in none of them on this branch.
+            let fc = self.nodes.get(nd_data.idx).first_child.unwrap();
+            let mut children: [Vec<usize>; 4] = Default::default();
+            for entity_idx in batch {
+                let entity = *self.entities.get(entity_idx);
+                if self.storage == Storage::Split {
+                    let pieces = self.entity_pieces(&entity);
+                    for (quadrant, child) in children.iter_mut().enumerate() {
+                        let region = nd_data.child(fc, quadrant).region;
+                        if pieces.iter().any(|piece| region.receives(&self.root.region, piece)) {
+                            child.push(entity_idx);
+                        }
+                    }
+                    continue;
+                }
+                let region = Region {
+                    left: entity.left,
+                    top: entity.top,
+                    right: entity.right,
+                    bottom: entity.bottom,
+                };
+                match self.home_child(&nd_data, &region) {
+                    Some(child) => children[child.idx - fc].push(entity_idx),
+                    None => self.link(nd_data.idx, entity_idx),
+                }
+            }
+            for (quadrant, child) in children.into_iter().enumerate() {
+                if !child.is_empty() {
+                    to_process.push((nd_data.child(fc, quadrant), child));
+                }
+            }
+        }
+        Ok(handles)
+    }
+
+    /// Moves or resizes an entity while keeping its handle. Only the leaves
+    /// whose membership changes are touched, and nothing is relinked at all
+    /// when the new box overlaps the same leaves as the old one. A box that
+    /// stays within its single leaf, or its node without split storage, is
+    /// rewritten in place without looking up any leaves.
+    pub fn update(
+        &mut self,
+        entity: EntityHandle,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+    ) -> Result<(), QuadtreeError> {
+        self.check_handle(entity)?;
+        self.fit_bounds(x1, y1, x2, y2)?;
+
+        let entity_idx = entity.index;
+        let old = *self.entities.get(entity_idx);
+        let new = Entity {
+            left: x1,
+            top: y1,
+            right: x2,
+            bottom: y2,
+        };
+        if self.same_home(&old, &new) {
+            self.entities.set(entity_idx, new);
+            return Ok(());
+        }
+        let old_leaves = self.find_leaves(self.root, old.left, old.top, old.right, old.bottom);
+        let new_leaves = self.find_leaves(self.root, x1, y1, x2, y2);
+
+        // Store the new box first so that any leaf split below routes the
+        // entity by where it is now.
+        self.entities.set(entity_idx, new);
+
+        // Unlink from leaves the entity no longer overlaps.
+        for i in 0..old_leaves.cursor() {
+            let leaf = old_leaves.get(i);
+            if !Self::contains_leaf(&new_leaves, leaf.idx) {
+                node::unlink(&mut self.nodes, &mut self.entity_nodes, leaf.idx, entity_idx);
+            }
+        }
+
+        // Link into leaves the entity newly overlaps.
+        for i in 0..new_leaves.cursor() {
+            let leaf = *new_leaves.get(i);
+            if !Self::contains_leaf(&old_leaves, leaf.idx) {
+                self.leaf_insert(leaf, entity_idx);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns true if the handle refers to an entity that is still in the tree.
+    pub fn contains(&self, entity: EntityHandle) -> bool {
+        self.check_handle(entity).is_ok()
+    }
+
+    fn check_handle(&self, entity: EntityHandle) -> Result<(), QuadtreeError> {
+        node::check_handle(&self.entities, entity)
+    }
+
+    pub fn get(&self, entity: EntityHandle) -> Option<&T> {
+        match self.contains(entity) {
+            true => self.payloads[entity.index].as_ref(),
+            false => None,
+        }
+    }
+
+    pub fn get_mut(&mut self, entity: EntityHandle) -> Option<&mut T> {
+        match self.contains(entity) {
+            true => self.payloads[entity.index].as_mut(),
+            false => None,
+        }
+    }
+
+    /// Removes an entity, returning its box and payload.
+    pub fn remove(&mut self, entity: EntityHandle) -> Result<(Rect<S>, T), QuadtreeError> {
+        // Stale handles must not touch the entity now occupying their slot.
+        self.check_handle(entity)?;
+        let entity_idx = entity.index;
+        let payload = self.payloads[entity_idx]
+            .take()
+            .expect("entity in the tree has no payload");
+
         // Find the leaves.
-        let entity = self.entities.get(entity_idx);
+        let entity = *self.entities.get(entity_idx);
         let leaves = self.find_leaves(
             self.root,
             entity.left,
@@ -196,317 +880,1354 @@ impl Quadtree {
 
         // For each leaf node, remove the element node.
         for i in 0..leaves.cursor() {
-            let nd_data_idx = leaves.get(i).idx;
-
-            // Walk the list until we find the element node.
-            let mut node_idx = self.nodes.get(nd_data_idx).first_child;
-            let mut prev_index = None;
-            while node_idx.is_some()
-                && self.entity_nodes.get(node_idx.unwrap()).entity != entity_idx
-            {
-                prev_index = node_idx;
-                node_idx = self.entity_nodes.get(node_idx.unwrap()).next;
-            }
-
-            if node_idx.is_some() {
-                // Remove the element node.
-                let next_index = self.entity_nodes.get(node_idx.unwrap()).next;
-                if prev_index.is_none() {
-                    self.nodes.get_mut(nd_data_idx).first_child = next_index;
-                } else {
-                    self.entity_nodes.get_mut(prev_index.unwrap()).next = next_index;
-                }
-                self.entity_nodes.erase(node_idx.unwrap());
-
-                // Decrement the leaf element count.
-                let num_children = self.nodes.get(nd_data_idx).num_children.unwrap();
-                if num_children == 0 {
-                    self.nodes.get_mut(nd_data_idx).num_children = None;
-                } else {
-                    self.nodes.get_mut(nd_data_idx).num_children = Some(num_children - 1);
-                }
-            }
+            node::unlink(&mut self.nodes, &mut self.entity_nodes, leaves.get(i).idx, entity_idx);
         }
 
         // Remove the element.
         self.entities.erase(entity_idx);
+        let rect = Rect {
+            x1: entity.left,
+            y1: entity.top,
+            x2: entity.right,
+            y2: entity.bottom,
+        };
+        Ok((rect, payload))
     }
 
+    /// Collapses every branch whose leaves hold no more unique entities than
+    /// a single leaf may. Merges cascade upward, so a single call leaves
+    /// nothing to collapse.
     pub fn cleanup(&mut self) {
-        let mut to_process = List::<usize>::default();
+        self.cleanup_stack.clear();
+        self.cleanup_step(usize::MAX);
+    }
 
-        // Only process the root if it's not a leaf.
-        if self.nodes.get(self.root.idx).num_children.is_none() {
-            // Push the root index to the stack.
-            to_process.push(self.root.idx);
+    /// Runs cleanup for at most `max_nodes` node visits and returns true once
+    /// a whole pass over the tree has completed. An unfinished pass resumes
+    /// where it left off on the next call, even if the tree was modified in
+    /// between.
+    pub fn cleanup_step(&mut self, max_nodes: usize) -> bool {
+        // Drop the frames of branches that no longer exist. Everything above
+        // such a frame was below it, so it is gone as well.
+        let valid = self
+            .cleanup_stack
+            .iter()
+            .position(|frame| {
+                self.nodes.generation(frame.idx) != frame.generation
+                    || self.nodes.get(frame.idx).first_child.is_none()
+            })
+            .unwrap_or(self.cleanup_stack.len());
+        self.cleanup_stack.truncate(valid);
+
+        if self.cleanup_stack.is_empty() {
+            // Only process the root if it's not a leaf.
+            if self.nodes.get(self.root.idx).first_child.is_none() {
+                return true;
+            }
+            self.cleanup_stack.push(CleanupFrame {
+                idx: self.root.idx,
+                generation: self.nodes.generation(self.root.idx),
+                next_quadrant: 0,
+            });
         }
 
-        while to_process.cursor() > 0 {
-            // Pop a node from the stack.
-            let node_idx = to_process.pop();
-            let node = self.nodes.get(node_idx);
-            let mut num_empty_leaves = 0;
-
-            // Loop through the children.
-            for i in 0..4 {
-                let child_idx = node.first_child.unwrap() + i;
-                // Increment empty leaf count if the child is an empty
-                // leaf. Otherwise, if the child is a branch, add it to
-                // the stack to be processed in the next iteration.
-                let child_node = self.nodes.get(child_idx);
-                if child_node.num_children.is_none() {
-                    // Push the child index to the stack.
-                    to_process.push(child_idx);
-                } else if child_node.num_children.unwrap() == 0 {
-                    num_empty_leaves += 1;
+        // Walk the branches in post-order so that children are collapsed
+        // before their parent is considered.
+        let mut budget = max_nodes;
+        while budget > 0 {
+            let Some(frame) = self.cleanup_stack.last_mut() else {
+                return true;
+            };
+            budget -= 1;
+            let fc = self.nodes.get(frame.idx).first_child.unwrap();
+            if frame.next_quadrant < 4 {
+                let child_idx = fc + frame.next_quadrant;
+                frame.next_quadrant += 1;
+                if self.nodes.get(child_idx).first_child.is_some() {
+                    // Push the child branch to the stack.
+                    self.cleanup_stack.push(CleanupFrame {
+                        idx: child_idx,
+                        generation: self.nodes.generation(child_idx),
+                        next_quadrant: 0,
+                    });
                 }
+                continue;
             }
 
-            // If all the children were empty leaves, remove them and
-            // make this node the new empty leaf.
-            if num_empty_leaves == 4 {
-                // Remove all 4 children in reverse order so that they
-                // can be reclaimed on subsequent insertions in proper
-                // order.
-                let fc = node.first_child.unwrap();
-                self.nodes.erase(fc + 3);
-                self.nodes.erase(fc + 2);
-                self.nodes.erase(fc + 1);
-                self.nodes.erase(fc + 0);
-
-                // Make this node the new empty leaf.
-                self.nodes.get_mut(node_idx).first_child = None;
-                self.nodes.get_mut(node_idx).num_children = Some(0);
+            // If all the children are leaves that hold no more unique
+            // entities between them and this node than a single leaf may,
+            // remove them and make this node the leaf holding those entities.
+            let node_idx = frame.idx;
+            self.cleanup_stack.pop();
+            if node::has_only_leaves(&self.nodes, node_idx, 4) {
+                let unique = node::unique_leaf_entities(
+                    &self.nodes,
+                    &self.entity_nodes,
+                    node_idx,
+                    4,
+                    self.max_entities,
+                );
+                if let Some(entities) = unique {
+                    node::collapse(&mut self.nodes, &mut self.entity_nodes, node_idx, 4, &entities);
+                }
             }
         }
+        self.cleanup_stack.is_empty()
     }
 
-    pub fn query(&self, x1: f32, y1: f32, x2: f32, y2: f32) -> Vec<usize> {
+    pub fn query(
+        &self,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
         self.query_omit(x1, y1, x2, y2, None)
     }
 
     pub fn query_omit(
         &self,
-        x1: f32,
-        y1: f32,
-        x2: f32,
-        y2: f32,
-        omit_entity_id: Option<usize>,
-    ) -> Vec<usize> {
-        let mut out = Vec::<usize>::new();
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+        omit_entity: Option<EntityHandle>,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
+        let mut ctx = QueryContext::new();
+        let mut handles = Vec::new();
+        self.query_omit_into(&mut ctx, x1, y1, x2, y2, omit_entity, &mut handles)?;
+        Ok(self.with_payloads(handles))
+    }
+
+    /// Like [`Quadtree::query`], but reuses the buffers in `ctx` and writes
+    /// the handles into `out`, so repeated queries do not allocate once the
+    /// buffers have grown large enough. `out` is cleared first.
+    pub fn query_into(
+        &self,
+        ctx: &mut QueryContext<S>,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+        out: &mut Vec<EntityHandle>,
+    ) -> Result<(), QuadtreeError> {
+        self.query_omit_into(ctx, x1, y1, x2, y2, None, out)
+    }
 
+    /// Like [`Quadtree::query_omit`], but with the buffers of
+    /// [`Quadtree::query_into`].
+    #[allow(clippy::too_many_arguments)]
+    pub fn query_omit_into(
+        &self,
+        ctx: &mut QueryContext<S>,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+        omit_entity: Option<EntityHandle>,
+        out: &mut Vec<EntityHandle>,
+    ) -> Result<(), QuadtreeError> {
+        out.clear();
+        self.check_coordinates(x1, y1, x2, y2)?;
         // Find the leaves that intersect the specified query rectangle.
-        let q_left = x1 as i32;
-        let q_top = y1 as i32;
-        let q_right = x2 as i32;
-        let q_bottom = y2 as i32;
-        let leaves = self.find_leaves(self.root, q_left, q_top, q_right, q_bottom);
+        self.find_rect_leaves(ctx, x1, y1, x2, y2);
+        self.walk_leaves(ctx, omit_entity, self.rect_matches(x1, y1, x2, y2), |idx| {
+            out.push(self.handle(idx));
+        });
+        Ok(())
+    }
+
+    /// Returns a lazy iterator over the entities overlapping the box. Leaves
+    /// are only visited as the iterator advances. A box that
+    /// [`Quadtree::query`] would reject yields nothing.
+    pub fn query_iter(&self, x1: S, y1: S, x2: S, y2: S) -> QueryIter<'_, T, S> {
+        // Start empty and grow with the walk, so that short lived iterators
+        // such as the one behind `any_in` stay cheap.
+        let mut to_process = List::<NodeData<S>>::new(0);
+        let mut pieces = Pieces::default();
+        if self.check_coordinates(x1, y1, x2, y2).is_ok() {
+            to_process.push(self.root);
+            pieces = self.pieces(x1, y1, x2, y2);
+        }
+        QueryIter {
+            tree: self,
+            pieces,
+            piece: 0,
+            to_process,
+            leaf_region: self.root.region,
+            next_enode: None,
+        }
+    }
 
-        let mut seen = Vec::<bool>::new();
-        seen.resize(self.entities.cursor(), false);
+    /// Calls `f` for every entity overlapping the box until it returns
+    /// [`ControlFlow::Break`], whose value is then passed back to the caller.
+    /// Like [`Quadtree::query_iter`], a rejected box calls `f` for nothing.
+    pub fn query_each<B>(
+        &self,
+        x1: S,
+        y1: S,
+        x2: S,
+        y2: S,
+        mut f: impl FnMut(EntityHandle, &T) -> ControlFlow<B>,
+    ) -> ControlFlow<B> {
+        for (entity, payload) in self.query_iter(x1, y1, x2, y2) {
+            f(entity, payload)?;
+        }
+        ControlFlow::Continue(())
+    }
 
-        // For each leaf node, look for elements that intersect.
-        for i in 0..leaves.cursor() {
-            let nd_data_idx = leaves.get(i).idx;
+    /// Returns true if any entity overlaps the box, stopping at the first one.
+    /// A box that [`Quadtree::query`] would reject overlaps nothing.
+    pub fn any_in(&self, x1: S, y1: S, x2: S, y2: S) -> bool {
+        self.query_iter(x1, y1, x2, y2).next().is_some()
+    }
 
-            // Walk the list and add elements that intersect.
-            let mut next_enode_idx = self.nodes.get(nd_data_idx).first_child;
-            while next_enode_idx.is_some() {
-                let entity_node = self.entity_nodes.get(next_enode_idx.unwrap());
-                let entity = self.entities.get(entity_node.entity);
-                if !seen[entity_node.entity]
-                    && !(omit_entity_id.is_some() && entity_node.entity == omit_entity_id.unwrap())
-                    && Self::intersect(
-                        q_left,
-                        q_top,
-                        q_right,
-                        q_bottom,
-                        entity.left,
-                        entity.top,
-                        entity.right,
-                        entity.bottom,
-                    )
-                {
-                    out.push(entity_node.entity);
-                    seen[entity_node.entity] = true;
+    pub fn query_circle(
+        &self,
+        cx: S,
+        cy: S,
+        radius: S,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
+        self.query_circle_omit(cx, cy, radius, None)
+    }
+
+    /// Returns the entities whose boxes overlap the circle, skipping the
+    /// leaves that only the circle's bounding square would reach.
+    pub fn query_circle_omit(
+        &self,
+        cx: S,
+        cy: S,
+        radius: S,
+        omit_entity: Option<EntityHandle>,
+    ) -> Result<Vec<(EntityHandle, &T)>, QuadtreeError> {
+        let mut ctx = QueryContext::new();
+        let mut handles = Vec::new();
+        self.query_circle_omit_into(&mut ctx, cx, cy, radius, omit_entity, &mut handles)?;
+        Ok(self.with_payloads(handles))
+    }
+
+    /// Like [`Quadtree::query_circle_omit`], but with the buffers of
+    /// [`Quadtree::query_into`].
+    pub fn query_circle_omit_into(
+        &self,
+        ctx: &mut QueryContext<S>,
+        cx: S,
+        cy: S,
+        radius: S,
+        omit_entity: Option<EntityHandle>,
+        out: &mut Vec<EntityHandle>,
+    ) -> Result<(), QuadtreeError> {
+        out.clear();
+        if let Some(Ordering::Less) | None = radius.partial_cmp(&S::default()) {
+            return Err(QuadtreeError::NegativeRadius);
+        }
+        if ![cx, cy, radius].into_iter().all(S::is_finite) {
+            return Err(QuadtreeError::NonFiniteCoordinate);
+        }
+        let px = cx.to_f64();
+        let py = cy.to_f64();
+        let r_sq = radius.to_f64() * radius.to_f64();
+        self.find_leaves_into(&mut ctx.leaves, &mut ctx.to_process, self.root, |child| {
+            self.wrapped_distance_sq(&self.bounds(child), px, py) <= r_sq
+        });
+        let matches = |entity: &Entity<S>| self.entity_distance_sq(entity, px, py) <= r_sq;
+        self.walk_leaves(ctx, omit_entity, matches, |idx| {
+            out.push(self.handle(idx));
+        });
+        Ok(())
+    }
+
+    /// Returns the entity closest to the point along with its distance.
+    pub fn nearest(&self, x: S, y: S) -> Result<Option<(EntityHandle, &T, f64)>, QuadtreeError> {
+        Ok(self.k_nearest(x, y, 1)?.pop())
+    }
+
+    /// Returns up to `k` entities closest to the point, sorted by distance.
+    /// The distance to an entity is measured to the closest point of its
+    /// box, so entities containing the point are at distance zero.
+    pub fn k_nearest(
+        &self,
+        x: S,
+        y: S,
+        k: usize,
+    ) -> Result<Vec<(EntityHandle, &T, f64)>, QuadtreeError> {
+        let mut ctx = QueryContext::new();
+        let mut hits = Vec::new();
+        self.k_nearest_into(&mut ctx, x, y, k, &mut hits)?;
+        Ok(hits
+            .into_iter()
+            .map(|(entity, distance)| (entity, self.payload(entity.index), distance))
+            .collect())
+    }
+
+    /// Like [`Quadtree::k_nearest`], but reuses the buffers in `ctx` and
+    /// writes the handles and distances into `out`, which is cleared first.
+    pub fn k_nearest_into(
+        &self,
+        ctx: &mut QueryContext<S>,
+        x: S,
+        y: S,
+        k: usize,
+        out: &mut Vec<(EntityHandle, f64)>,
+    ) -> Result<(), QuadtreeError> {
+        out.clear();
+        if !(x.is_finite() && y.is_finite()) {
+            return Err(QuadtreeError::NonFiniteCoordinate);
+        }
+        if k == 0 {
+            return Ok(());
+        }
+        let px = x.to_f64();
+        let py = y.to_f64();
+        ctx.begin(self.entities.cursor());
+
+        // Best-first search: node distances are lower bounds for every
+        // entity below them, so when an entity is popped nothing left in
+        // the heap can be closer.
+        ctx.heap.clear();
+        ctx.heap.push(Ranked {
+            distance: self.wrapped_distance_sq(&self.bounds(&self.root), px, py),
+            candidate: Candidate::Node(self.root),
+        });
+        while let Some(ranked) = ctx.heap.pop() {
+            match ranked.candidate {
+                Candidate::Entity(entity_idx) => {
+                    out.push((self.handle(entity_idx), ranked.distance.sqrt()));
+                    if out.len() == k {
+                        break;
+                    }
+                }
+                Candidate::Node(nd_data) => {
+                    let node = self.nodes.get(nd_data.idx);
+                    if let Some(fc) = node.first_child {
+                        for quadrant in 0..4 {
+                            let child = nd_data.child(fc, quadrant);
+                            ctx.heap.push(Ranked {
+                                distance: self.wrapped_distance_sq(&self.bounds(&child), px, py),
+                                candidate: Candidate::Node(child),
+                            });
+                        }
+                    }
+                    let mut next_enode_idx = node.first_entity;
+                    while let Some(enode_idx) = next_enode_idx {
+                        let entity_node = self.entity_nodes.get(enode_idx);
+                        if !ctx.is_seen(entity_node.entity) {
+                            ctx.mark_seen(entity_node.entity);
+                            let entity = self.entities.get(entity_node.entity);
+                            ctx.heap.push(Ranked {
+                                distance: self.entity_distance_sq(entity, px, py),
+                                candidate: Candidate::Entity(entity_node.entity),
+                            });
+                        }
+                        next_enode_idx = entity_node.next;
+                    }
                 }
-                next_enode_idx = entity_node.next;
             }
         }
+        Ok(())
+    }
+
+    /// Casts a ray from `(ox, oy)` along `(dx, dy)` and returns the first
+    /// entity hit within `max_distance`, along with the distance to the hit.
+    /// The direction does not need to be normalized, and `max_distance` may
+    /// be infinite.
+    pub fn raycast(
+        &self,
+        ox: f64,
+        oy: f64,
+        dx: f64,
+        dy: f64,
+        max_distance: f64,
+    ) -> Result<Option<(EntityHandle, &T, f64)>, QuadtreeError> {
+        let mut ctx = QueryContext::new();
+        let mut first = None;
+        self.ray_hits(&mut ctx, ox, oy, dx, dy, max_distance, |entity_idx, t| {
+            first = Some((self.handle(entity_idx), self.payload(entity_idx), t));
+            false
+        })?;
+        Ok(first)
+    }
+
+    /// Like [`Quadtree::raycast`], but returns every entity hit, ordered
+    /// from the closest to the farthest.
+    pub fn raycast_all(
+        &self,
+        ox: f64,
+        oy: f64,
+        dx: f64,
+        dy: f64,
+        max_distance: f64,
+    ) -> Result<Vec<(EntityHandle, &T, f64)>, QuadtreeError> {
+        let mut ctx = QueryContext::new();
+        let mut hits = Vec::new();
+        self.raycast_all_into(&mut ctx, ox, oy, dx, dy, max_distance, &mut hits)?;
+        Ok(hits
+            .into_iter()
+            .map(|(entity, t)| (entity, self.payload(entity.index), t))
+            .collect())
+    }
+
+    /// Like [`Quadtree::raycast_all`], but reuses the buffers in `ctx` and
+    /// writes the handles and distances into `out`, which is cleared first.
+    #[allow(clippy::too_many_arguments)]
+    pub fn raycast_all_into(
+        &self,
+        ctx: &mut QueryContext<S>,
+        ox: f64,
+        oy: f64,
+        dx: f64,
+        dy: f64,
+        max_distance: f64,
+        out: &mut Vec<(EntityHandle, f64)>,
+    ) -> Result<(), QuadtreeError> {
+        out.clear();
+        self.ray_hits(ctx, ox, oy, dx, dy, max_distance, |entity_idx, t| {
+            out.push((self.handle(entity_idx), t));
+            true
+        })
+    }
+
+    /// Returns every unordered pair of overlapping entities exactly once.
+    pub fn collision_pairs(&self) -> Vec<(EntityHandle, EntityHandle)> {
+        let mut out = Vec::<(EntityHandle, EntityHandle)>::new();
+        self.collision_pairs_each(|a, b| out.push((a, b)));
         out
     }
 
-    pub fn traverse(&self, visitor: &mut impl Visitor) {
-        let mut to_process = List::<NodeData>::default();
+    /// Calls `f` once for every unordered pair of overlapping entities, with
+    /// the lower entity id first. With [`Storage::Split`] each leaf's list is
+    /// walked once; otherwise every entity is stored once and pairs of nodes
+    /// are walked top down, skipping those whose bounds do not meet.
+    pub fn collision_pairs_each(&self, mut f: impl FnMut(EntityHandle, EntityHandle)) {
+        if self.storage != Storage::Split {
+            self.stored_once_pairs(&mut f);
+            return;
+        }
+
+        let mut to_process = List::<NodeData<S>>::default();
+        to_process.push(self.root);
+
+        while to_process.cursor() > 0 {
+            let nd_data = to_process.pop();
+            let node = self.nodes.get(nd_data.idx);
+            if let Some(fc) = node.first_child {
+                for quadrant in 0..4 {
+                    to_process.push(nd_data.child(fc, quadrant));
+                }
+                continue;
+            }
+
+            let mut a_enode_idx = node.first_entity;
+            while let Some(a_idx) = a_enode_idx {
+                let a_node = self.entity_nodes.get(a_idx);
+                let a = self.entities.get(a_node.entity);
+                let mut b_enode_idx = a_node.next;
+                while let Some(b_idx) = b_enode_idx {
+                    let b_node = self.entity_nodes.get(b_idx);
+                    let b = self.entities.get(b_node.entity);
+                    // Large entities share several leaves, so a pair is
+                    // only reported by the leaf owning the top left corner
+                    // of the overlap.
+                    if let Some((x, y)) = self.overlap_corner(a, b) {
+                        if nd_data.region.owns(&self.root.region, x, y) {
+                            let (first, second) = self.ordered(a_node.entity, b_node.entity);
+                            f(first, second);
+                        }
+                    }
+                    b_enode_idx = b_node.next;
+                }
+                a_enode_idx = a_node.next;
+            }
+        }
+    }
+
+    /// Finds the overlapping pairs of a tree that stores every entity once.
+    /// An entity can only overlap those in its own node, its ancestors, or
+    /// the subtrees whose bounds meet its node's, so those are compared.
+    fn stored_once_pairs(&self, f: &mut impl FnMut(EntityHandle, EntityHandle)) {
+        let mut to_process = vec![PairTask::Within(self.root)];
+        while let Some(task) = to_process.pop() {
+            match task {
+                PairTask::Within(nd_data) => {
+                    self.list_pairs(nd_data.idx, None, f);
+                    let Some(fc) = self.nodes.get(nd_data.idx).first_child else {
+                        continue;
+                    };
+                    for quadrant in 0..4 {
+                        let child = nd_data.child(fc, quadrant);
+                        to_process.push(PairTask::Within(child));
+                        to_process.push(PairTask::Across(nd_data, child));
+                        for other in quadrant + 1..4 {
+                            to_process.push(PairTask::Between(child, nd_data.child(fc, other)));
+                        }
+                    }
+                }
+                PairTask::Across(a, b) => {
+                    if !self.bounds(&a).intersects(&self.bounds(&b)) {
+                        continue;
+                    }
+                    self.list_pairs(a.idx, Some(b.idx), f);
+                    if let Some(fc) = self.nodes.get(b.idx).first_child {
+                        for quadrant in 0..4 {
+                            to_process.push(PairTask::Across(a, b.child(fc, quadrant)));
+                        }
+                    }
+                }
+                PairTask::Between(a, b) => {
+                    if !self.bounds(&a).intersects(&self.bounds(&b)) {
+                        continue;
+                    }
+                    to_process.push(PairTask::Across(a, b));
+                    if let Some(fc) = self.nodes.get(a.idx).first_child {
+                        for quadrant in 0..4 {
+                            to_process.push(PairTask::Between(a.child(fc, quadrant), b));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reports the overlapping pairs within the list of `a_idx`, or between
+    /// it and the list of `b_idx`.
+    fn list_pairs(
+        &self,
+        a_idx: usize,
+        b_idx: Option<usize>,
+        f: &mut impl FnMut(EntityHandle, EntityHandle),
+    ) {
+        let mut a_enode_idx = self.nodes.get(a_idx).first_entity;
+        while let Some(a_enode) = a_enode_idx {
+            let a_node = self.entity_nodes.get(a_enode);
+            let a = self.entities.get(a_node.entity);
+            let mut b_enode_idx = match b_idx {
+                Some(b_idx) => self.nodes.get(b_idx).first_entity,
+                None => a_node.next,
+            };
+            while let Some(b_enode) = b_enode_idx {
+                let b_node = self.entity_nodes.get(b_enode);
+                if a.region().intersects(&self.entities.get(b_node.entity).region()) {
+                    let (first, second) = self.ordered(a_node.entity, b_node.entity);
+                    f(first, second);
+                }
+                b_enode_idx = b_node.next;
+            }
+            a_enode_idx = a_node.next;
+        }
+    }
+
+    /// Returns the handles of two entities, the lower id first.
+    fn ordered(&self, a: usize, b: usize) -> (EntityHandle, EntityHandle) {
+        match a < b {
+            true => (self.handle(a), self.handle(b)),
+            false => (self.handle(b), self.handle(a)),
+        }
+    }
+
+    /// Returns the top left corner of where two entities overlap. Wrapped
+    /// entities may overlap in several places, and only the first pair of
+    /// pieces that meet counts, so the pair has a single owner.
+    fn overlap_corner(&self, a: &Entity<S>, b: &Entity<S>) -> Option<(S, S)> {
+        let b_pieces = self.entity_pieces(b);
+        self.entity_pieces(a).iter().find_map(|a| {
+            let b = b_pieces.iter().find(|b| a.intersects(b))?;
+            let x = if a.left > b.left { a.left } else { b.left };
+            let y = if a.top > b.top { a.top } else { b.top };
+            Some((x, y))
+        })
+    }
+
+    pub fn traverse(&self, visitor: &mut impl Visitor<T, S>) {
+        let mut to_process = List::<NodeData<S>>::default();
         to_process.push(self.root);
 
         while to_process.cursor() > 0 {
             let nd_data = to_process.pop();
 
             let node = self.nodes.get(nd_data.idx);
-            if node.num_children.is_none() {
+            if let Some(fc) = node.first_child {
                 // Push the children of the branch to the stack.
-                let fc = self.nodes.get(nd_data.idx).first_child.unwrap();
-                let qx = nd_data.hx >> 1;
-                let qy = nd_data.hy >> 1;
-                let l = nd_data.x - qx;
-                let t = nd_data.y - qy;
-                let r = nd_data.x + qx;
-                let b = nd_data.y + qy;
-                to_process.push(NodeData {
-                    idx: fc + 0,
-                    depth: nd_data.depth + 1,
-                    x: l,
-                    y: t,
-                    hx: qx,
-                    hy: qy,
-                });
-                to_process.push(NodeData {
-                    idx: fc + 1,
-                    depth: nd_data.depth + 1,
-                    x: r,
-                    y: t,
-                    hx: qx,
-                    hy: qy,
-                });
-                to_process.push(NodeData {
-                    idx: fc + 2,
-                    depth: nd_data.depth + 1,
-                    x: l,
-                    y: b,
-                    hx: qx,
-                    hy: qy,
-                });
-                to_process.push(NodeData {
-                    idx: fc + 3,
-                    depth: nd_data.depth + 1,
-                    x: r,
-                    y: b,
-                    hx: qx,
-                    hy: qy,
-                });
+                for quadrant in 0..4 {
+                    to_process.push(nd_data.child(fc, quadrant));
+                }
                 visitor.branch(
                     nd_data.depth,
                     nd_data.idx,
                     fc,
                     nd_data.x,
                     nd_data.y,
-                    nd_data.hx << 1,
-                    nd_data.hy << 1,
+                    nd_data.hx + nd_data.hx,
+                    nd_data.hy + nd_data.hy,
                 );
             } else {
                 visitor.leaf(
                     nd_data.depth,
                     nd_data.idx,
-                    node.num_children,
-                    node.first_child,
+                    node.num_entities,
+                    node.first_entity,
                     nd_data.x,
                     nd_data.y,
-                    nd_data.hx << 1,
-                    nd_data.hy << 1,
+                    nd_data.hx + nd_data.hx,
+                    nd_data.hy + nd_data.hy,
                 );
-                let mut node_idx = self.nodes.get(nd_data.idx).first_child;
-                while node_idx != None {
-                    let entity_node = self.entity_nodes.get(node_idx.unwrap());
-                    let entity = self.entities.get(entity_node.entity);
-                    let w = entity.right - entity.left;
-                    let h = entity.bottom - entity.top;
-                    let x = entity.left + (w >> 1);
-                    let y = entity.top + (h >> 1);
-                    visitor.entity(
-                        entity_node.entity,
-                        node_idx.unwrap(),
-                        entity_node.next,
-                        x,
-                        y,
-                        w,
-                        h,
-                    );
-                    node_idx = entity_node.next
+            }
+
+            // Branches only hold entities of their own without split storage.
+            let mut node_idx = node.first_entity;
+            while let Some(enode_idx) = node_idx {
+                let entity_node = self.entity_nodes.get(enode_idx);
+                let entity = self.entities.get(entity_node.entity);
+                let w = entity.right - entity.left;
+                let h = entity.bottom - entity.top;
+                let x = entity.left + w.half();
+                let y = entity.top + h.half();
+                visitor.entity(
+                    self.handle(entity_node.entity),
+                    self.payload(entity_node.entity),
+                    enode_idx,
+                    entity_node.next,
+                    x,
+                    y,
+                    w,
+                    h,
+                );
+                node_idx = entity_node.next
+            }
+        }
+    }
+
+    /// Counts nodes, links and free slots, and how entities spread over the
+    /// leaves. This walks the whole tree.
+    pub fn stats(&self) -> QuadtreeStats {
+        let mut stats = QuadtreeStats {
+            entities: self.entities.cursor() - self.entities.vacancies(),
+            entity_nodes: self.entity_nodes.cursor() - self.entity_nodes.vacancies(),
+            free_nodes: self.nodes.vacancies(),
+            free_entity_nodes: self.entity_nodes.vacancies(),
+            free_entities: self.entities.vacancies(),
+            ..QuadtreeStats::default()
+        };
+        let mut to_process = List::<NodeData<S>>::default();
+        to_process.push(self.root);
+        while to_process.cursor() > 0 {
+            let nd_data = to_process.pop();
+            let node = self.nodes.get(nd_data.idx);
+            stats.nodes += 1;
+            if let Some(fc) = node.first_child {
+                stats.branches += 1;
+                for quadrant in 0..4 {
+                    to_process.push(nd_data.child(fc, quadrant));
+                }
+                continue;
+            }
+            stats.leaves += 1;
+            let depth = nd_data.depth as usize;
+            if stats.depth_histogram.len() <= depth {
+                stats.depth_histogram.resize(depth + 1, 0);
+            }
+            stats.depth_histogram[depth] += 1;
+            stats.max_entities_per_leaf = stats.max_entities_per_leaf.max(node.num_entities);
+            stats.mean_entities_per_leaf += node.num_entities as f64;
+        }
+        stats.mean_entities_per_leaf /= stats.leaves as f64;
+        if stats.entities > 0 {
+            stats.duplication_factor = stats.entity_nodes as f64 / stats.entities as f64;
+        }
+        stats
+    }
+
+    /// Checks the bookkeeping of the tree and reports the first broken
+    /// invariant. Every reachable node and link must be live and every live
+    /// one reachable, branches must have 4 live children, each node's count
+    /// must match its list, and every entity must be linked into exactly the
+    /// nodes it belongs in: each leaf its box overlaps with split storage,
+    /// otherwise its single home node.
+    ///
+    /// This walks the whole tree, so it is meant for tests and debug builds.
+    pub fn validate(&self) -> Result<(), IntegrityError> {
+        // The nodes each entity is linked into, in the order they were found.
+        let mut linked = vec![Vec::<usize>::new(); self.entities.cursor()];
+        let mut seen_nodes = vec![false; self.nodes.cursor()];
+        let mut seen_enodes = vec![false; self.entity_nodes.cursor()];
+
+        let mut to_process = List::<usize>::default();
+        to_process.push(self.root.idx);
+        while to_process.cursor() > 0 {
+            let node_idx = to_process.pop();
+            if !self.nodes.is_occupied(node_idx) {
+                return Err(IntegrityError::VacantNode { node: node_idx });
+            }
+            if seen_nodes[node_idx] {
+                return Err(IntegrityError::NodeReachedTwice { node: node_idx });
+            }
+            seen_nodes[node_idx] = true;
+
+            let node = self.nodes.get(node_idx);
+            if let Some(fc) = node.first_child {
+                if self.storage == Storage::Split && node.first_entity.is_some() {
+                    return Err(IntegrityError::BranchHoldsEntities { node: node_idx });
+                }
+                for child_idx in fc..fc + 4 {
+                    to_process.push(child_idx);
+                }
+            }
+
+            let mut count = 0;
+            let mut next_enode_idx = node.first_entity;
+            while let Some(enode_idx) = next_enode_idx {
+                if !self.entity_nodes.is_occupied(enode_idx) {
+                    return Err(IntegrityError::VacantEntityNode {
+                        node: node_idx,
+                        entity_node: enode_idx,
+                    });
+                }
+                if seen_enodes[enode_idx] {
+                    return Err(IntegrityError::EntityNodeReachedTwice {
+                        entity_node: enode_idx,
+                    });
+                }
+                seen_enodes[enode_idx] = true;
+
+                let entity_node = self.entity_nodes.get(enode_idx);
+                if !self.entities.is_occupied(entity_node.entity) {
+                    return Err(IntegrityError::VacantEntity {
+                        node: node_idx,
+                        entity: entity_node.entity,
+                    });
+                }
+                if linked[entity_node.entity].contains(&node_idx) {
+                    return Err(IntegrityError::DuplicateLink {
+                        entity: entity_node.entity,
+                        node: node_idx,
+                    });
+                }
+                linked[entity_node.entity].push(node_idx);
+                count += 1;
+                next_enode_idx = entity_node.next;
+            }
+            if count != node.num_entities {
+                return Err(IntegrityError::EntityCountMismatch {
+                    node: node_idx,
+                    counted: node.num_entities,
+                    linked: count,
+                });
+            }
+        }
+
+        let unreachable_node = (0..self.nodes.cursor())
+            .find(|&i| self.nodes.is_occupied(i) && !seen_nodes[i]);
+        if let Some(node) = unreachable_node {
+            return Err(IntegrityError::UnreachableNode { node });
+        }
+        let unreachable_enode = (0..self.entity_nodes.cursor())
+            .find(|&i| self.entity_nodes.is_occupied(i) && !seen_enodes[i]);
+        if let Some(entity_node) = unreachable_enode {
+            return Err(IntegrityError::UnreachableEntityNode { entity_node });
+        }
+
+        for (entity_idx, linked) in linked.iter().enumerate() {
+            let live = self.entities.is_occupied(entity_idx);
+            if live != matches!(self.payloads.get(entity_idx), Some(Some(_))) {
+                return Err(IntegrityError::PayloadMismatch { entity: entity_idx });
+            }
+            if !live {
+                continue;
+            }
+            let entity = self.entities.get(entity_idx);
+            let expected = self.find_leaves(
+                self.root,
+                entity.left,
+                entity.top,
+                entity.right,
+                entity.bottom,
+            );
+            for i in 0..expected.cursor() {
+                let node = expected.get(i).idx;
+                if !linked.contains(&node) {
+                    return Err(IntegrityError::EntityNotLinked {
+                        entity: entity_idx,
+                        node,
+                    });
+                }
+            }
+            let extra = linked.iter().find(|&&node| !Self::contains_leaf(&expected, node));
+            if let Some(&node) = extra {
+                return Err(IntegrityError::EntityWronglyLinked {
+                    entity: entity_idx,
+                    node,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Walks the nodes crossed by the ray front to back and reports each hit
+    /// in order until `on_hit` returns false. Rays do not wrap around the
+    /// edges of the root, but do hit the wrapped parts of entities.
+    #[allow(clippy::too_many_arguments)]
+    fn ray_hits(
+        &self,
+        ctx: &mut QueryContext<S>,
+        ox: f64,
+        oy: f64,
+        dx: f64,
+        dy: f64,
+        max_distance: f64,
+        mut on_hit: impl FnMut(usize, f64) -> bool,
+    ) -> Result<(), QuadtreeError> {
+        if ![ox, oy, dx, dy].into_iter().all(f64::is_finite) || max_distance.is_nan() {
+            return Err(QuadtreeError::NonFiniteCoordinate);
+        }
+        let length = dx.hypot(dy);
+        if length == 0.0 || max_distance < 0.0 {
+            return Ok(());
+        }
+        let dx = dx / length;
+        let dy = dy / length;
+        ctx.begin(self.entities.cursor());
+
+        // Nodes and entities are ranked by where the ray enters them. Any
+        // entity hit before another one lies in a leaf the ray enters
+        // earlier, so hits come off the heap in order.
+        ctx.heap.clear();
+        if let Some(t) = self.bounds(&self.root).ray_entry(ox, oy, dx, dy, max_distance) {
+            ctx.heap.push(Ranked {
+                distance: t,
+                candidate: Candidate::Node(self.root),
+            });
+        }
+        while let Some(ranked) = ctx.heap.pop() {
+            match ranked.candidate {
+                Candidate::Entity(entity_idx) => {
+                    if !on_hit(entity_idx, ranked.distance) {
+                        return Ok(());
+                    }
+                }
+                Candidate::Node(nd_data) => {
+                    let node = self.nodes.get(nd_data.idx);
+                    if let Some(fc) = node.first_child {
+                        for quadrant in 0..4 {
+                            let child = nd_data.child(fc, quadrant);
+                            let bounds = self.bounds(&child);
+                            if let Some(t) = bounds.ray_entry(ox, oy, dx, dy, max_distance) {
+                                ctx.heap.push(Ranked {
+                                    distance: t,
+                                    candidate: Candidate::Node(child),
+                                });
+                            }
+                        }
+                    }
+                    let mut next_enode_idx = node.first_entity;
+                    while let Some(enode_idx) = next_enode_idx {
+                        let entity_node = self.entity_nodes.get(enode_idx);
+                        if !ctx.is_seen(entity_node.entity) {
+                            ctx.mark_seen(entity_node.entity);
+                            let entity = self.entities.get(entity_node.entity);
+                            let t = self
+                                .entity_pieces(entity)
+                                .iter()
+                                .filter_map(|piece| piece.ray_entry(ox, oy, dx, dy, max_distance))
+                                .reduce(f64::min);
+                            if let Some(t) = t {
+                                ctx.heap.push(Ranked {
+                                    distance: t,
+                                    candidate: Candidate::Entity(entity_node.entity),
+                                });
+                            }
+                        }
+                        next_enode_idx = entity_node.next;
+                    }
                 }
             }
         }
+        Ok(())
+    }
+
+    /// Like [`Quadtree::check_bounds`], but first grows the root until it
+    /// contains the box if the tree is allowed to grow and does not wrap.
+    fn fit_bounds(&mut self, x1: S, y1: S, x2: S, y2: S) -> Result<(), QuadtreeError> {
+        self.check_coordinates(x1, y1, x2, y2)?;
+        if self.auto_grow && !self.wrap && x1 < x2 && y1 < y2 {
+            let entity = Region {
+                left: x1,
+                top: y1,
+                right: x2,
+                bottom: y2,
+            };
+            self.grow_to(&entity)?;
+        }
+        self.check_bounds(x1, y1, x2, y2)
+    }
+
+    /// Grows the root until it contains `entity`. Fails without changing
+    /// anything if the grown root would no longer fit the coordinate type.
+    fn grow_to(&mut self, entity: &Region<S>) -> Result<(), QuadtreeError> {
+        // Try the whole growth on a copy of the root first.
+        let mut root = self.root;
+        while !root.region.contains(entity) {
+            root = Self::grown_root(&root, entity)?.0;
+        }
+        while !self.root.region.contains(entity) {
+            self.grow_towards(entity)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the root doubled towards `entity`, along with the quadrant
+    /// of it taken by the old root.
+    fn grown_root(
+        root: &NodeData<S>,
+        entity: &Region<S>,
+    ) -> Result<(NodeData<S>, usize), QuadtreeError> {
+        let (x, x_side) = match entity.left < root.region.left {
+            true => (root.region.left, 1),
+            false => (root.region.right, 0),
+        };
+        let (y, y_side) = match entity.top < root.region.top {
+            true => (root.region.top, 2),
+            false => (root.region.bottom, 0),
+        };
+        let (hx, hy) = Self::checked_root(x, y, root.hx + root.hx, root.hy + root.hy)
+            .ok_or(QuadtreeError::CoordinateOverflow)?;
+        Ok((NodeData::root(root.idx, x, y, hx, hy), x_side | y_side))
+    }
+
+    /// Rejects boxes with coordinates that are not finite, or that the tree
+    /// could not do arithmetic on without overflowing.
+    fn check_coordinates(&self, x1: S, y1: S, x2: S, y2: S) -> Result<(), QuadtreeError> {
+        if ![x1, y1, x2, y2].into_iter().all(S::is_finite) {
+            return Err(QuadtreeError::NonFiniteCoordinate);
+        }
+        let size = x2.checked_sub(x1).and(y2.checked_sub(y1));
+        // Wrapped boxes are measured from the edges of the root.
+        let offset = match self.wrap {
+            true => x1
+                .checked_sub(self.root.region.left)
+                .and(y1.checked_sub(self.root.region.top)),
+            false => Some(x1),
+        };
+        match size.and(offset) {
+            Some(_) => Ok(()),
+            None => Err(QuadtreeError::CoordinateOverflow),
+        }
+    }
+
+    /// Doubles the root towards the box, making the old root one of the
+    /// children of the new one. The root keeps its node index and the old
+    /// root's contents move into a new child, so every other node keeps its
+    /// index as well.
+    /// Fails without changing anything if the grown root would no longer
+    /// fit the coordinate type.
+    fn grow_towards(&mut self, entity: &Region<S>) -> Result<(), QuadtreeError> {
+        let root = self.root;
+        let (new_root, quadrant) = Self::grown_root(&root, entity)?;
+
+        // Initialize 4 child nodes and move the old root into its quadrant.
+        let fc = self.nodes.insert(Node::default());
+        self.nodes.insert(Node::default());
+        self.nodes.insert(Node::default());
+        self.nodes.insert(Node::default());
+        let old_root = *self.nodes.get(root.idx);
+        self.nodes.set(fc + quadrant, old_root);
+        self.nodes.set(
+            root.idx,
+            Node {
+                first_child: Some(fc),
+                ..Node::default()
+            },
+        );
+
+        self.root = new_root;
+        self.max_depth = Self::limit_depth(
+            self.root.hx + self.root.hx,
+            self.root.hy + self.root.hy,
+            self.min_cell_size,
+            self.depth_limit,
+        );
+
+        // Paths of an unfinished cleanup pass no longer start at the root.
+        self.cleanup_stack.clear();
+        Ok(())
+    }
+
+    /// Returns the half sizes of a root with the given center and half
+    /// sizes if its edges and its full size fit the coordinate type.
+    pub(crate) fn checked_root(x: S, y: S, hx: S, hy: S) -> Option<(S, S)> {
+        x.checked_sub(hx)?;
+        x.checked_add(hx)?;
+        y.checked_sub(hy)?;
+        y.checked_add(hy)?;
+        hx.checked_add(hx)?;
+        hy.checked_add(hy)?;
+        Some((hx, hy))
+    }
+
+    fn check_bounds(&self, x1: S, y1: S, x2: S, y2: S) -> Result<(), QuadtreeError> {
+        if x1 >= x2 || y1 >= y2 {
+            return Err(QuadtreeError::InsertHasInvertedBounds)
+        }
+        if !self.wrap && (x1 < self.root.x - self.root.hx || x2 > self.root.x + self.root.hx ||
+            y1 < self.root.y - self.root.hy || y2 > self.root.y + self.root.hy) {
+            return Err(QuadtreeError::InsertIsOutOfBounds)
+        }
+        Ok(())
+    }
+
+    fn contains_leaf(leaves: &List<NodeData<S>>, idx: usize) -> bool {
+        (0..leaves.cursor()).any(|i| leaves.get(i).idx == idx)
+    }
+
+    /// Walks the entities of the nodes found in `ctx` and passes every
+    /// entity accepted by `matches` to `emit`. Entities spanning several
+    /// leaves are only reported once, which only takes a record of seen
+    /// entities with split storage.
+    fn walk_leaves(
+        &self,
+        ctx: &mut QueryContext<S>,
+        omit_entity: Option<EntityHandle>,
+        matches: impl Fn(&Entity<S>) -> bool,
+        mut emit: impl FnMut(usize),
+    ) {
+        ctx.begin(self.entities.cursor());
+        let dedupe = self.storage == Storage::Split;
+
+        // For each leaf node, look for elements that match.
+        for i in 0..ctx.leaves.cursor() {
+            let nd_data_idx = ctx.leaves.get(i).idx;
+
+            // Walk the list and add elements that match.
+            let mut next_enode_idx = self.nodes.get(nd_data_idx).first_entity;
+            while let Some(enode_idx) = next_enode_idx {
+                let entity_node = self.entity_nodes.get(enode_idx);
+                if !(dedupe && ctx.is_seen(entity_node.entity))
+                    && omit_entity != Some(self.handle(entity_node.entity))
+                    && matches(self.entities.get(entity_node.entity))
+                {
+                    emit(entity_node.entity);
+                    if dedupe {
+                        ctx.mark_seen(entity_node.entity);
+                    }
+                }
+                next_enode_idx = entity_node.next;
+            }
+        }
+    }
+
+    fn rect_matches(&self, x1: S, y1: S, x2: S, y2: S) -> impl Fn(&Entity<S>) -> bool + '_ {
+        let query = self.pieces(x1, y1, x2, y2);
+        move |entity| {
+            self.entity_pieces(entity)
+                .iter()
+                .any(|piece| query.iter().any(|part| piece.intersects(part)))
+        }
+    }
+
+    /// Cuts a box into its parts inside the root, see [`Pieces`].
+    fn pieces(&self, left: S, top: S, right: S, bottom: S) -> Pieces<S> {
+        let mut pieces = Pieces::default();
+        if !self.wrap {
+            pieces.regions[0] = Region {
+                left,
+                top,
+                right,
+                bottom,
+            };
+            pieces.len = 1;
+            return pieces;
+        }
+        let root = self.root.region;
+        let (xs, num_xs) = Self::wrap_span(root.left, root.right, left, right);
+        let (ys, num_ys) = Self::wrap_span(root.top, root.bottom, top, bottom);
+        for &(left, right) in &xs[..num_xs] {
+            for &(top, bottom) in &ys[..num_ys] {
+                pieces.regions[pieces.len] = Region {
+                    left,
+                    top,
+                    right,
+                    bottom,
+                };
+                pieces.len += 1;
+            }
+        }
+        pieces
+    }
+
+    /// Wraps the span `start..end` into `lo..hi`, returning one or two spans.
+    fn wrap_span(lo: S, hi: S, start: S, end: S) -> ([(S, S); 2], usize) {
+        let size = hi - lo;
+        let length = end - start;
+        if length >= size {
+            // The span goes all the way around.
+            return ([(lo, hi), (lo, hi)], 1);
+        }
+        if length < S::default() {
+            // Inverted spans are left as they are.
+            return ([(start, end), (start, end)], 1);
+        }
+        // Measure against the room left before `hi` rather than computing
+        // the wrapped end, which may not fit the coordinate type.
+        let start = lo + (start - lo).rem_euclid(size);
+        let room = hi - start;
+        match length <= room {
+            true => ([(start, start + length), (start, start + length)], 1),
+            false => ([(start, hi), (lo, lo + (length - room))], 2),
+        }
+    }
+
+    fn entity_pieces(&self, entity: &Entity<S>) -> Pieces<S> {
+        self.pieces(entity.left, entity.top, entity.right, entity.bottom)
+    }
+
+    /// Squared distance from a point to a region, measured the short way
+    /// around the edges of a wrapping tree.
+    fn wrapped_distance_sq(&self, region: &Region<f64>, px: f64, py: f64) -> f64 {
+        if !self.wrap {
+            return region.distance_sq(px, py);
+        }
+        let root = self.root.region.to_f64();
+        let w = root.right - root.left;
+        let h = root.bottom - root.top;
+        let px = root.left + (px - root.left).rem_euclid(w);
+        let py = root.top + (py - root.top).rem_euclid(h);
+        let mut distance = f64::INFINITY;
+        for sx in [-w, 0.0, w] {
+            for sy in [-h, 0.0, h] {
+                distance = distance.min(region.distance_sq(px + sx, py + sy));
+            }
+        }
+        distance
+    }
+
+    /// Squared distance from a point to the closest part of an entity.
+    fn entity_distance_sq(&self, entity: &Entity<S>, px: f64, py: f64) -> f64 {
+        self.entity_pieces(entity)
+            .iter()
+            .map(|piece| self.wrapped_distance_sq(&piece.to_f64(), px, py))
+            .fold(f64::INFINITY, f64::min)
+    }
+
+    /// Returns the Morton code of the center of a box on a 65536 cell grid
+    /// laid over the root.
+    fn morton_key(&self, x1: S, y1: S, x2: S, y2: S) -> u32 {
+        let root = self.root.region.to_f64();
+        let cell = |lo: S, hi: S, root_lo: f64, root_hi: f64| {
+            let center = (lo.to_f64() + hi.to_f64()) * 0.5;
+            ((center - root_lo) / (root_hi - root_lo) * 65535.0).clamp(0.0, 65535.0) as u32
+        };
+        // Spread the 16 bits of a cell out to every other bit.
+        let spread = |mut v: u32| {
+            v = (v | (v << 8)) & 0x00ff_00ff;
+            v = (v | (v << 4)) & 0x0f0f_0f0f;
+            v = (v | (v << 2)) & 0x3333_3333;
+            (v | (v << 1)) & 0x5555_5555
+        };
+        spread(cell(x1, x2, root.left, root.right))
+            | spread(cell(y1, y2, root.top, root.bottom)) << 1
+    }
+
+    fn handle(&self, entity_idx: usize) -> EntityHandle {
+        EntityHandle {
+            index: entity_idx,
+            generation: self.entities.generation(entity_idx),
+        }
+    }
+
+    fn payload(&self, entity_idx: usize) -> &T {
+        self.payloads[entity_idx]
+            .as_ref()
+            .expect("entity in the tree has no payload")
+    }
+
+    fn with_payloads(&self, handles: Vec<EntityHandle>) -> Vec<(EntityHandle, &T)> {
+        handles
+            .into_iter()
+            .map(|entity| (entity, self.payload(entity.index)))
+            .collect()
+    }
+
+    /// Returns the nodes below `start_node` that an entity with the given
+    /// box is linked into: every leaf it overlaps, or with other storage
+    /// modes the single node it belongs to.
+    fn find_leaves(
+        &self,
+        start_node: NodeData<S>,
+        left: S,
+        top: S,
+        right: S,
+        bottom: S,
+    ) -> List<NodeData<S>> {
+        let mut leaves = List::<NodeData<S>>::default();
+        if self.storage != Storage::Split {
+            let entity = Region {
+                left,
+                top,
+                right,
+                bottom,
+            };
+            leaves.push(self.home_node(start_node, &entity));
+            return leaves;
+        }
+        // The parts of a wrapped box are routed separately, so that one
+        // part never drags another into a branch it does not overlap.
+        let pieces = self.pieces(left, top, right, bottom);
+        let mut to_process = List::<NodeData<S>>::default();
+        self.find_leaves_into(&mut leaves, &mut to_process, start_node, |child| {
+            pieces.iter().any(|piece| child.region.receives(&self.root.region, piece))
+        });
+        leaves
+    }
+
+    /// Returns the node below `start_node` that an entity stored once
+    /// belongs to.
+    fn home_node(&self, start_node: NodeData<S>, entity: &Region<S>) -> NodeData<S> {
+        let mut nd_data = start_node;
+        while let Some(child) = self.home_child(&nd_data, entity) {
+            nd_data = child;
+        }
+        nd_data
+    }
+
+    /// Returns true if both boxes are linked into the same single node: the
+    /// same leaf with split storage, otherwise the same home node. Only a
+    /// single path down the tree is walked.
+    fn same_home(&self, old: &Entity<S>, new: &Entity<S>) -> bool {
+        if self.storage != Storage::Split {
+            let old_home = self.home_node(self.root, &old.region());
+            return old_home.idx == self.home_node(self.root, &new.region()).idx;
+        }
+        // Wrapped boxes cut into several parts are left to the slow path.
+        let (old_pieces, new_pieces) = (self.entity_pieces(old), self.entity_pieces(new));
+        if old_pieces.len != 1 || new_pieces.len != 1 {
+            return false;
+        }
+        let (old, new) = (old_pieces.regions[0], new_pieces.regions[0]);
+        let root = &self.root.region;
+        let mut nd_data = self.root;
+        while let Some(fc) = self.nodes.get(nd_data.idx).first_child {
+            let mut next = None;
+            for quadrant in 0..4 {
+                let child = nd_data.child(fc, quadrant);
+                let receives_old = child.region.receives(root, &old);
+                if receives_old != child.region.receives(root, &new) {
+                    return false;
+                }
+                if receives_old {
+                    if next.is_some() {
+                        return false;
+                    }
+                    next = Some(child);
+                }
+            }
+            match next {
+                Some(child) => nd_data = child,
+                None => return false,
+            }
+        }
+        true
+    }
+
+    /// Returns the child of a branch that an entity stored once should move
+    /// down into, or `None` if the entity belongs to `nd_data` itself.
+    fn home_child(&self, nd_data: &NodeData<S>, entity: &Region<S>) -> Option<NodeData<S>> {
+        let fc = self.nodes.get(nd_data.idx).first_child?;
+        match self.storage {
+            Storage::Split => None,
+            Storage::Loose(looseness) => {
+                // Follow the center, which like any point goes left or up
+                // when it is on a split line.
+                let cx = entity.left + (entity.right - entity.left).half();
+                let cy = entity.top + (entity.bottom - entity.top).half();
+                let quadrant = (cx > nd_data.x) as usize | ((cy > nd_data.y) as usize) << 1;
+                let child = nd_data.child(fc, quadrant);
+                match child.region.loosen(looseness).contains(&entity.to_f64()) {
+                    true => Some(child),
+                    false => None,
+                }
+            }
+            Storage::Enclosing => {
+                let x_side = match (entity.right <= nd_data.x, entity.left >= nd_data.x) {
+                    (true, _) => 0,
+                    (_, true) => 1,
+                    _ => return None,
+                };
+                let y_side = match (entity.bottom <= nd_data.y, entity.top >= nd_data.y) {
+                    (true, _) => 0,
+                    (_, true) => 2,
+                    _ => return None,
+                };
+                Some(nd_data.child(fc, x_side | y_side))
+            }
+        }
     }
 
-    fn intersect(l1: i32, t1: i32, r1: i32, b1: i32, l2: i32, t2: i32, r2: i32, b2: i32) -> bool {
-        l2 <= r1 && r2 >= l1 && t2 <= b1 && b2 >= t1
+    /// Returns the area that entities stored in the node can reach.
+    fn bounds(&self, nd_data: &NodeData<S>) -> Region<f64> {
+        match self.storage {
+            Storage::Split | Storage::Enclosing => nd_data.region.to_f64(),
+            Storage::Loose(looseness) => nd_data.region.loosen(looseness),
+        }
     }
 
-    fn find_leaves(
+    /// Returns true if a query box may find entities in the child node.
+    fn query_overlaps(&self, child: &NodeData<S>, query: &Region<S>) -> bool {
+        match self.storage {
+            Storage::Split => child.region.receives(&self.root.region, query),
+            // Entities are not cut at split lines, so one touching the
+            // edge of its node can be found from across that edge.
+            Storage::Loose(_) | Storage::Enclosing => {
+                self.bounds(child).intersects(&query.to_f64())
+            }
+        }
+    }
+
+    fn find_rect_leaves(&self, ctx: &mut QueryContext<S>, left: S, top: S, right: S, bottom: S) {
+        let pieces = self.pieces(left, top, right, bottom);
+        self.find_leaves_into(
+            &mut ctx.leaves,
+            &mut ctx.to_process,
+            self.root,
+            |child| pieces.iter().any(|piece| self.query_overlaps(child, piece)),
+        );
+    }
+
+    /// Collects the leaves below `start_node` into `leaves`, descending only
+    /// into the children for which `overlaps(child)` holds.
+    /// Branches holding entities of their own are collected as well. Both
+    /// buffers are cleared first.
+    fn find_leaves_into(
         &self,
-        start_node: NodeData,
-        left: i32,
-        top: i32,
-        right: i32,
-        bottom: i32,
-    ) -> List<NodeData> {
-        let mut leaves = List::<NodeData>::default();
-        let mut to_process = List::<NodeData>::default();
+        leaves: &mut List<NodeData<S>>,
+        to_process: &mut List<NodeData<S>>,
+        start_node: NodeData<S>,
+        overlaps: impl Fn(&NodeData<S>) -> bool,
+    ) {
+        leaves.clear();
+        to_process.clear();
         to_process.push(start_node);
 
         while to_process.cursor() > 0 {
             let nd_data = to_process.pop();
-            if self.nodes.get(nd_data.idx).num_children.is_some() {
-                leaves.push(nd_data);
-            } else {
-                let fc = self.nodes.get(nd_data.idx).first_child.unwrap();
-                let qx = nd_data.hx >> 1;
-                let qy = nd_data.hy >> 1;
-                let l = nd_data.x - qx;
-                let t = nd_data.y - qy;
-                let r = nd_data.x + qx;
-                let b = nd_data.y + qy;
-
-                if top <= nd_data.y {
-                    if left <= nd_data.x {
-                        to_process.push(NodeData {
-                            idx: fc + 0,
-                            depth: nd_data.depth + 1,
-                            x: l,
-                            y: t,
-                            hx: qx,
-                            hy: qy,
-                        });
-                    }
-                    if right > nd_data.x {
-                        to_process.push(NodeData {
-                            idx: fc + 1,
-                            depth: nd_data.depth + 1,
-                            x: r,
-                            y: t,
-                            hx: qx,
-                            hy: qy,
-                        });
-                    }
+            let node = self.nodes.get(nd_data.idx);
+            match node.first_child {
+                None => {
+                    leaves.push(nd_data);
                 }
-                if bottom > nd_data.y {
-                    if left <= nd_data.x {
-                        to_process.push(NodeData {
-                            idx: fc + 2,
-                            depth: nd_data.depth + 1,
-                            x: l,
-                            y: b,
-                            hx: qx,
-                            hy: qy,
-                        });
+                Some(fc) => {
+                    if node.first_entity.is_some() {
+                        leaves.push(nd_data);
                     }
-                    if right > nd_data.x {
-                        to_process.push(NodeData {
-                            idx: fc + 3,
-                            depth: nd_data.depth + 1,
-                            x: r,
-                            y: b,
-                            hx: qx,
-                            hy: qy,
-                        });
+                    for quadrant in 0..4 {
+                        let child = nd_data.child(fc, quadrant);
+                        if overlaps(&child) {
+                            to_process.push(child);
+                        }
                     }
                 }
             }
         }
-        return leaves;
     }
 
-    fn node_insert(&mut self, start_node: NodeData, entity_idx: usize) {
+    fn node_insert(&mut self, start_node: NodeData<S>, entity_idx: usize) {
         let entity = self.entities.get(entity_idx);
         let leaves = self.find_leaves(
             start_node,
@@ -522,133 +2243,114 @@ impl Quadtree {
         }
     }
 
-    fn leaf_insert(&mut self, node_data: NodeData, entity_idx: usize) {
-        let first_child = self.nodes.get(node_data.idx).first_child;
-        let e_node = self.entity_nodes.push(EntityNode {
+    fn link(&mut self, node_idx: usize, entity_idx: usize) {
+        node::link(&mut self.nodes, &mut self.entity_nodes, node_idx, entity_idx);
+    }
+
+    fn leaf_insert(&mut self, node_data: NodeData<S>, entity_idx: usize) {
+        let first_entity = self.nodes.get(node_data.idx).first_entity;
+        let e_node = self.entity_nodes.insert(EntityNode {
             entity: entity_idx,
-            next: first_child,
+            next: first_entity,
         });
-        self.nodes.get_mut(node_data.idx).first_child = Some(e_node);
+        self.nodes.get_mut(node_data.idx).first_entity = Some(e_node);
 
-        // If the leaf is full, split it.
-        if self.nodes.get(node_data.idx).num_children.unwrap() == (self.max_entities as usize)
+        // If the leaf is full, split it. Branches only get here without
+        // split storage, for entities that fit in none of their children.
+        let node = self.nodes.get(node_data.idx);
+        if node.first_child.is_none()
+            && node.num_entities == (self.max_entities as usize)
             && node_data.depth < self.max_depth
         {
-            // Transfer elements from the leaf node to a list of elements.
-            let mut entities = List::<usize>::default();
-            while self.nodes.get(node_data.idx).first_child.is_some() {
-                let index = self.nodes.get(node_data.idx).first_child;
-                let e_node = *self.entity_nodes.get(index.unwrap());
-
-                // Pop off the element node from the leaf and remove it from the qt.
-                self.nodes.get_mut(node_data.idx).first_child = e_node.next;
-                self.entity_nodes.erase(index.unwrap());
-
-                // Insert element to the list.
-                entities.push(e_node.entity);
-            }
-
-            // Initialize 4 child nodes.
-            let fc = self.nodes.insert(Node::default());
-            self.nodes.insert(Node::default());
-            self.nodes.insert(Node::default());
-            self.nodes.insert(Node::default());
-
-            self.nodes.get_mut(node_data.idx).first_child = Some(fc);
-            self.nodes.get_mut(node_data.idx).num_children = None;
+            let entities = node::split(&mut self.nodes, &mut self.entity_nodes, node_data.idx, 4);
 
             // Transfer the elements in the former leaf node to its new children.
             for i in 0..entities.cursor() {
                 self.node_insert(node_data, *entities.get(i));
             }
         } else {
-            // Increment the leaf element count.
-            let num_children = self.nodes.get_mut(node_data.idx).num_children.unwrap();
-            self.nodes.get_mut(node_data.idx).num_children = Some(num_children + 1);
+            // Increment the node element count.
+            self.nodes.get_mut(node_data.idx).num_entities += 1;
         }
     }
 }
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[derive(Debug)]
-    struct TestVisitor {
-        entities: Vec<usize>,
-        leaves: Vec<u8>,
-        branches: Vec<u8>,
-    }
+/// A lazy iterator over the entities overlapping a box, returned by
+/// [`Quadtree::query_iter`].
+pub struct QueryIter<'a, T, S: Coordinate = f32> {
+    tree: &'a Quadtree<T, S>,
+    pieces: Pieces<S>,
+    piece: usize,
+    to_process: List<NodeData<S>>,
+    leaf_region: Region<S>,
+    next_enode: Option<usize>,
+}
 
-    impl TestVisitor {
-        fn new() -> Self {
-            Self {
-                entities: Vec::new(),
-                leaves: Vec::new(),
-                branches: Vec::new(),
+impl<'a, T, S: Coordinate> Iterator for QueryIter<'a, T, S> {
+    type Item = (EntityHandle, &'a T);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let tree = self.tree;
+        loop {
+            // Walk the current leaf's list.
+            let q = self.pieces.regions[self.piece];
+            while let Some(enode_idx) = self.next_enode {
+                let entity_node = tree.entity_nodes.get(enode_idx);
+                self.next_enode = entity_node.next;
+                let entity_pieces = tree.entity_pieces(tree.entities.get(entity_node.entity));
+                let Some(entity) = entity_pieces.iter().find(|piece| piece.intersects(&q)) else {
+                    continue;
+                };
+                // An entity spanning several leaves is reported only by
+                // the leaf owning the top left corner of its overlap with
+                // the query, so no record of seen entities is needed.
+                // Other storage modes keep a single copy of each entity anyway.
+                let x = if entity.left > q.left { entity.left } else { q.left };
+                let y = if entity.top > q.top { entity.top } else { q.top };
+                if tree.storage != Storage::Split
+                    || self.leaf_region.owns(&tree.root.region, x, y)
+                {
+                    // When wrapping, entities overlapping an earlier part
+                    // of the query were reported with that part.
+                    let reported = self.pieces.iter().take(self.piece).any(|earlier| {
+                        entity_pieces.iter().any(|piece| piece.intersects(earlier))
+                    });
+                    if !reported {
+                        return Some((tree.handle(entity_node.entity), tree.payload(entity_node.entity)));
+                    }
+                }
             }
-        }
 
-        fn reset(&mut self) {
-            self.entities.clear();
-            self.leaves.clear();
-            self.branches.clear();
-            println!("\n/////// RESET ///////\n")
-        }
-
-        fn assert_counts(&self, entities: usize, leaves: usize, branches: usize) {
-            assert_eq!(self.entities.len(), entities);
-            assert_eq!(self.leaves.len(), leaves);
-            assert_eq!(self.branches.len(), branches);
-        }
-    }
-
-    impl Visitor for TestVisitor {
-        fn entity(
-            &mut self,
-            entity_id: usize,
-            idx: usize,
-            next_entity: Option<usize>,
-            _x: i32,
-            _y: i32,
-            _width: i32,
-            _height: i32,
-        ) {
-            println!("----[EN: {entity_id} idx:{idx}->{next_entity:?}]");
-            self.entities.push(entity_id);
-        }
-
-        fn leaf(
-            &mut self,
-            depth: u8,
-            idx: usize,
-            num_children: Option<usize>,
-            first_entity: Option<usize>,
-            x: i32,
-            y: i32,
-            w: i32,
-            h: i32,
-        ) {
-            println!("--[LF: {idx}, children: {num_children:?}, first_entity: {first_entity:?}, d:{depth}, x:{x}, y:{y}, w:{w}, h:{h}]");
-            self.leaves.push(depth);
-        }
-
-        fn branch(
-            &mut self,
-            depth: u8,
-            idx: usize,
-            first_leaf: usize,
-            x: i32,
-            y: i32,
-            w: i32,
-            h: i32,
-        ) {
-            println!(
-                "[BR: {idx},  d:{depth}, first_leaf:{first_leaf}, x:{x}, y:{y}, w:{w}, h:{h}]"
-            );
-            self.branches.push(depth);
+            // Move on to the next leaf, or the next part of the query.
+            if self.to_process.cursor() == 0 {
+                if self.piece + 1 >= self.pieces.len {
+                    return None;
+                }
+                self.piece += 1;
+                self.to_process.push(tree.root);
+                continue;
+            }
+            let nd_data = self.to_process.pop();
+            let node = tree.nodes.get(nd_data.idx);
+            self.leaf_region = nd_data.region;
+            self.next_enode = node.first_entity;
+            if let Some(fc) = node.first_child {
+                for quadrant in 0..4 {
+                    let child = nd_data.child(fc, quadrant);
+                    if tree.query_overlaps(&child, &q) {
+                        self.to_process.push(child);
+                    }
+                }
+            }
         }
     }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{ids, lcg, sorted_ids, TestVisitor};
 
     #[test]
     fn calc_max_depth() {
@@ -669,14 +2371,14 @@ mod tests {
             let power: i32 = 1 << x;
             let next_lower = power + (power >> 1);
             let prev_upper = next_lower - 1;
-            assert_eq!(Quadtree::calc_max_depth(prev_upper, prev_upper), prev_x);
-            assert_eq!(Quadtree::calc_max_depth(next_lower, next_lower), x);
+            assert_eq!(Quadtree::<(), i32>::calc_max_depth(prev_upper, prev_upper), prev_x);
+            assert_eq!(Quadtree::<(), i32>::calc_max_depth(next_lower, next_lower), x);
         }
     }
 
     #[test]
-    fn new() {
-        let qt = Quadtree::new(10.0, 15.0, 100.0, 100.0, 8);
+    fn new() -> Result<(), QuadtreeError> {
+        let qt = Quadtree::<(), i32>::new(10, 15, 100, 100, 8)?;
         assert_eq!(qt.max_depth, 6);
         assert_eq!(qt.max_entities, 8);
         assert_eq!(qt.root.depth, 0);
@@ -688,13 +2390,53 @@ mod tests {
         assert_eq!(qt.entities.cursor(), 0);
         assert_eq!(qt.entity_nodes.cursor(), 0);
         assert_eq!(qt.nodes.cursor(), 1);
+        Ok(())
+    }
+
+    #[test]
+    fn default_depth() -> Result<(), QuadtreeError> {
+        // Integer trees stop at cells of 2 units, float trees only at the
+        // depth limit of their type.
+        assert_eq!(Quadtree::<(), i32>::new(0, 0, 100, 100, 8)?.max_depth, 6);
+        assert_eq!(Quadtree::<(), i64>::new(0, 0, 100, 100, 8)?.max_depth, 6);
+        assert_eq!(Quadtree::<(), f32>::new(0.0, 0.0, 100.0, 100.0, 8)?.max_depth, 16);
+        assert_eq!(Quadtree::<(), f64>::new(0.0, 0.0, 100.0, 100.0, 8)?.max_depth, 32);
+
+        // The old float limit is one builder setting away.
+        let qt = QuadtreeBuilder::new(0.0, 0.0, 100.0, 100.0)
+            .min_cell_size(2.0)
+            .build::<()>()?;
+        assert_eq!(qt.max_depth, 6);
+        Ok(())
+    }
+
+    #[test]
+    fn sub_integer_precision()-> Result<(), QuadtreeError> {
+        // A normalized 0..1 world centered at 0.5.
+        let mut qt = Quadtree::new(0.5, 0.5, 1.0, 1.0, 1)?;
+        assert_eq!(qt.max_depth, f64::MAX_DEPTH);
+
+        let a = qt.insert(0.2, 0.2, 0.4, 0.4, "a")?;
+        let b = qt.insert(0.6, 0.6, 0.8, 0.8, "b")?;
+        let c = qt.insert(0.41, 0.61, 0.49, 0.69, "c")?;
+
+        // Splits happen well below a single unit.
+        let mut tv = TestVisitor::new();
+        qt.traverse(&mut tv);
+        assert!(!tv.branches.is_empty());
+
+        assert_eq!(qt.query(0.25, 0.25, 0.3, 0.3)?, vec![(a, &"a")]);
+        assert_eq!(qt.query(0.7, 0.7, 0.75, 0.75)?, vec![(b, &"b")]);
+        assert_eq!(qt.query(0.45, 0.65, 0.46, 0.66)?, vec![(c, &"c")]);
+        assert!(qt.query(0.5, 0.5, 0.55, 0.55)?.is_empty());
+        Ok(())
     }
 
     #[test]
     fn insert_and_traverse() -> Result<(), QuadtreeError> {
-        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4);
-        let entity = qt.insert(-40.0, -40.0, 40.0, 40.0)?;
-        assert_eq!(entity, 0);
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        let entity = qt.insert(-40.0, -40.0, 40.0, 40.0, ())?;
+        assert_eq!(entity.index, 0);
 
         /***
         |-----------------------|
@@ -716,229 +2458,1079 @@ mod tests {
         tv.assert_counts(1, 1, 0);
         tv.reset();
 
-        // Fill each leaf, which should trigger subdivides
-        // NW
+        // Fill each leaf, which should trigger subdivides
+        // NW
+        /***
+        |-----------------------|
+        | x x | x x |           |
+        |-----|-----|     x     |
+        | x x | x x |           |
+        |-----------|-----------|
+        |           |           |
+        |     x     |     x     |
+        |           |           |
+        |-----------------------|
+         */
+        qt.insert(-40.0, 30.0, -30.0, 40.0, ())?;
+        qt.insert(-40.0, 10.0, -30.0, 20.0, ())?;
+        qt.insert(-20.0, 30.0, -10.0, 40.0, ())?;
+        qt.insert(-20.0, 10.0, -10.0, 20.0, ())?;
+        qt.traverse(&mut tv);
+        tv.assert_counts(11, 7, 2);
+        tv.reset();
+
+        // NE
+        /***
+        |-----------------------|
+        | x x | x x | x x | x x |
+        |-----|-----|-----|-----|
+        | x x | x x | x x | x x |
+        |-----------|-----------|
+        |           |           |
+        |     x     |     x     |
+        |           |           |
+        |-----------------------|
+         */
+        qt.insert(30.0, 30.0, 40.0, 40.0, ())?;
+        qt.insert(30.0, 10.0, 40.0, 20.0, ())?;
+        qt.insert(10.0, 30.0, 20.0, 40.0, ())?;
+        qt.insert(10.0, 10.0, 20.0, 20.0, ())?;
+        qt.traverse(&mut tv);
+        tv.assert_counts(18, 10, 3);
+        tv.reset();
+
+        // SW
+        /***
+        |-----------------------|
+        | x x | x x | x x | x x |
+        |-----|-----|-----|-----|
+        | x x | x x | x x | x x |
+        |-----|-----|-----------|
+        | x x | x x |           |
+        |-----|-----|     x     |
+        | x x | x x |           |
+        |-----------------------|
+         */
+        qt.insert(-40.0, -40.0, -30.0, -30.0, ())?;
+        qt.insert(-40.0, -20.0, -30.0, -10.0, ())?;
+        qt.insert(-20.0, -40.0, -10.0, -30.0, ())?;
+        qt.insert(-20.0, -20.0, -10.0, -10.0, ())?;
+        qt.traverse(&mut tv);
+        tv.assert_counts(25, 13, 4);
+        tv.reset();
+
+        // SE
+        /***
+        |-----------------------|
+        | x x | x x | x x | x x |
+        |-----|-----|-----|-----|
+        | x x | x x | x x | x x |
+        |-----|-----|-----|-----|
+        | x x | x x | x x | x x |
+        |-----|-----|-----|-----|
+        | x x | x x | x x | x x |
+        |-----------------------|
+         */
+        qt.insert(30.0, -40.0, 40.0, -30.0, ())?;
+        qt.insert(30.0, -20.0, 40.0, -10.0, ())?;
+        qt.insert(10.0, -40.0, 20.0, -30.0, ())?;
+        qt.insert(10.0, -20.0, 20.0, -10.0, ())?;
+        qt.traverse(&mut tv);
+        tv.assert_counts(32, 16, 5);
+        tv.reset();
+        Ok(())
+    }
+
+    #[test]
+    fn insert_error_handling() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+
+        // entity box checks
+        let x2_less_than_x1 = qt.insert(1.0, 1.0, -1.0, 2.0, ());
+        let x2_same_as_x1 = qt.insert(1.0, 1.0, 1.0, 2.0, ());
+        let y2_less_than_y1 = qt.insert(1.0, 1.0, 2.0, -1.0, ());
+        let y2_same_as_y1 = qt.insert(1.0, 1.0, 2.0, 1.0, ());
+        assert!(x2_less_than_x1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
+        assert!(x2_same_as_x1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
+        assert!(y2_less_than_y1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
+        assert!(y2_same_as_y1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
+
+        // out of bounds checks
+        let x1_out_of_bounds = qt.insert(-51.0, 0.0, 0.0, 1.0, ());
+        let x2_out_of_bounds = qt.insert(0.0, 0.0, 51.0, 1.0, ());
+        let y1_out_of_bounds = qt.insert(0.0, -51.0, 1.0, 0.0, ());
+        let y2_out_of_bounds = qt.insert(0.0, 0.0, 1.0, 51.0, ());
+        assert!(x1_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
+        assert!(x2_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
+        assert!(y1_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
+        assert!(y2_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
+
+        // non-finite coordinate checks, which NaN would otherwise slip past
+        let x1_nan = qt.insert(f32::NAN, 0.0, 1.0, 1.0, ());
+        let y2_infinite = qt.insert(0.0, 0.0, 1.0, f32::INFINITY, ());
+        let query_nan = qt.query(0.0, f32::NAN, 1.0, 1.0);
+        assert!(x1_nan.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(y2_infinite.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(query_nan.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(qt.query(-50.0, -50.0, 50.0, 50.0)?.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn invalid_coordinates() -> Result<(), QuadtreeError> {
+        // root checks
+        let nan_root = Quadtree::<(), f64>::new(f64::NAN, 0.0, 10.0, 10.0, 4);
+        let empty_root = Quadtree::<(), f64>::new(0.0, 0.0, 0.0, 10.0, 4);
+        let negative_root = Quadtree::<(), f64>::new(0.0, 0.0, 10.0, -10.0, 4);
+        let narrow_root = Quadtree::<(), i32>::new(0, 0, 1, 10, 4);
+        let overflowing_root = Quadtree::<(), i32>::new(i32::MAX - 10, 0, 100, 100, 4);
+        let infinite_root = Quadtree::<(), f32>::new(f32::MAX, 0.0, f32::MAX, 10.0, 4);
+        assert!(nan_root.is_err_and(|e| e == QuadtreeError::NonFiniteCoordinate));
+        assert!(empty_root.is_err_and(|e| e == QuadtreeError::InvalidRootSize));
+        assert!(negative_root.is_err_and(|e| e == QuadtreeError::InvalidRootSize));
+        assert!(narrow_root.is_err_and(|e| e == QuadtreeError::InvalidRootSize));
+        assert!(overflowing_root.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        assert!(infinite_root.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+
+        // boxes too large to measure
+        let mut qt = Quadtree::<(), i32>::new(0, 0, 100, 100, 4)?;
+        let too_wide = qt.insert(i32::MIN, 0, i32::MAX, 1, ());
+        let query_too_wide = qt.query(0, i32::MIN, 1, i32::MAX);
+        assert!(too_wide.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        assert!(query_too_wide.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+
+        // growing past the range of the coordinate type leaves the tree as is
+        qt.set_auto_grow(true)?;
+        let a = qt.insert(10, 10, 20, 20, ())?;
+        let out_of_range = qt.insert(i32::MAX - 10, 0, i32::MAX, 1, ());
+        assert!(out_of_range.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        assert_eq!(ids(qt.query(0, 0, 30, 30)?), vec![a.index]);
+        qt.insert(1_000_000, 1_000_000, 1_000_001, 1_000_001, ())?;
+
+        // every query checks its input, and those that cannot report an
+        // error find nothing
+        let mut qt = Quadtree::<(), f32>::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        qt.insert(-10.0, -10.0, 10.0, 10.0, ())?;
+        let (mut ctx, mut out, mut hits) = (QueryContext::new(), Vec::new(), Vec::new());
+        let non_finite = |e| e == QuadtreeError::NonFiniteCoordinate;
+        let (nan, inf) = (f32::NAN, f32::INFINITY);
+        assert!(qt.query_into(&mut ctx, nan, 0.0, 1.0, 1.0, &mut out).is_err_and(non_finite));
+        let omit_into = qt.query_omit_into(&mut ctx, 0.0, 0.0, inf, 1.0, None, &mut out);
+        assert!(omit_into.is_err_and(non_finite));
+        assert!(qt.query_circle(0.0, 0.0, inf).is_err_and(non_finite));
+        assert!(qt.query_circle_omit(inf, 0.0, 1.0, None).is_err_and(non_finite));
+        assert!(qt.nearest(0.0, nan).is_err_and(non_finite));
+        assert!(qt.k_nearest_into(&mut ctx, nan, 0.0, 1, &mut hits).is_err_and(non_finite));
+        assert!(qt.raycast(0.0, 0.0, f64::NAN, 0.0, 10.0).is_err_and(non_finite));
+        assert!(qt.raycast_all(f64::INFINITY, 0.0, 1.0, 0.0, 10.0).is_err_and(non_finite));
+        let all_into = qt.raycast_all_into(&mut ctx, 0.0, 0.0, 1.0, 0.0, f64::NAN, &mut hits);
+        assert!(all_into.is_err_and(non_finite));
+        assert_eq!(qt.raycast(-50.0, 0.0, 1.0, 0.0, f64::INFINITY)?.map(|hit| hit.2), Some(40.0));
+        assert_eq!(qt.query_iter(nan, -50.0, 50.0, 50.0).count(), 0);
+        assert!(!qt.any_in(-50.0, -50.0, inf, 50.0));
+        let flow = qt.query_each(-50.0, nan, 50.0, 50.0, |_, _| ControlFlow::Break(()));
+        assert_eq!(flow, ControlFlow::Continue(()));
+        Ok(())
+    }
+
+    #[test]
+    fn query_and_omit() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+
+        // Cover almost entire quadtree root
+        let entity = qt.insert(-40.0, -40.0, 40.0, 40.0, ())?;
+        let q = ids(qt.query(-10.0, -10.0, 10.0, 10.0)?);
+        assert!(q.contains(&0));
+
+        // NW
+        qt.insert(-40.0, 30.0, -30.0, 40.0, ())?;
+        qt.insert(-40.0, 10.0, -30.0, 20.0, ())?;
+        qt.insert(-20.0, 30.0, -10.0, 40.0, ())?;
+        qt.insert(-20.0, 10.0, -10.0, 20.0, ())?;
+        let q = ids(qt.query(-50.0, 0.0, 0.0, 50.0)?);
+        let q_omit = ids(qt.query_omit(-50.0, 0.0, 0.0, 50.0, Some(entity))?);
+        assert_eq!(q.len(), 5);
+        assert!(q.contains(&0));
+        assert!(q.contains(&1));
+        assert!(q.contains(&2));
+        assert!(q.contains(&3));
+        assert!(q.contains(&4));
+        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+
+        // NE
+        qt.insert(30.0, 30.0, 40.0, 40.0, ())?;
+        qt.insert(30.0, 10.0, 40.0, 20.0, ())?;
+        qt.insert(10.0, 30.0, 20.0, 40.0, ())?;
+        qt.insert(10.0, 10.0, 20.0, 20.0, ())?;
+        let q = ids(qt.query(0.0, 0.0, 50.0, 50.0)?);
+        let q_omit = ids(qt.query_omit(0.0, 0.0, 50.0, 50.0, Some(entity))?);
+        assert_eq!(q.len(), 5);
+        assert!(q.contains(&0));
+        assert!(q.contains(&5));
+        assert!(q.contains(&6));
+        assert!(q.contains(&7));
+        assert!(q.contains(&8));
+        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+
+        // SW
+        qt.insert(-40.0, -40.0, -30.0, -30.0, ())?;
+        qt.insert(-40.0, -20.0, -30.0, -10.0, ())?;
+        qt.insert(-20.0, -40.0, -10.0, -30.0, ())?;
+        qt.insert(-20.0, -20.0, -10.0, -10.0, ())?;
+        let q = ids(qt.query(-50.0, -50.0, 0.0, 0.0)?);
+        let q_omit = ids(qt.query_omit(-50.0, -50.0, 0.0, 0.0, Some(entity))?);
+        assert_eq!(q.len(), 5);
+        assert!(q.contains(&0));
+        assert!(q.contains(&9));
+        assert!(q.contains(&10));
+        assert!(q.contains(&11));
+        assert!(q.contains(&12));
+        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+
+        // SE
+        qt.insert(30.0, -40.0, 40.0, -30.0, ())?;
+        qt.insert(30.0, -20.0, 40.0, -10.0, ())?;
+        qt.insert(10.0, -40.0, 20.0, -30.0, ())?;
+        qt.insert(10.0, -20.0, 20.0, -10.0, ())?;
+        let q = ids(qt.query(0.0, -50.0, 50.0, 0.0)?);
+        let q_omit = ids(qt.query_omit(0.0, -50.0, 50.0, 0.0, Some(entity))?);
+        assert_eq!(q.len(), 5);
+        assert!(q.contains(&0));
+        assert!(q.contains(&13));
+        assert!(q.contains(&14));
+        assert!(q.contains(&15));
+        assert!(q.contains(&16));
+        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+
+        // Center
+        let q = ids(qt.query(-10.0, -10.0, 10.0, 10.0)?);
+        let q_omit = ids(qt.query_omit(-10.0, -10.0, 10.0, 10.0, Some(entity))?);
+        assert_eq!(q.len(), 5);
+        assert!(q.contains(&0));
+        assert!(q.contains(&4));
+        assert!(q.contains(&8));
+        assert!(q.contains(&12));
+        assert!(q.contains(&16));
+        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+        Ok(())
+    }
+
+    #[test]
+    fn payloads() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        let a = qt.insert(-40.0, -40.0, -30.0, -30.0, "a")?;
+        let b = qt.insert(10.0, 10.0, 20.0, 20.0, "b")?;
+        assert_eq!(qt.get(a), Some(&"a"));
+        assert_eq!(qt.get(b), Some(&"b"));
+
+        *qt.get_mut(b).unwrap() = "c";
+        assert_eq!(qt.query(0.0, 0.0, 50.0, 50.0)?, vec![(b, &"c")]);
+
+        let rect = Rect {
+            x1: -40.0,
+            y1: -40.0,
+            x2: -30.0,
+            y2: -30.0,
+        };
+        assert_eq!(qt.remove(a), Ok((rect, "a")));
+        assert_eq!(qt.remove(a), Err(QuadtreeError::EntityAlreadyRemoved));
+        assert_eq!(qt.get(a), None);
+        assert!(qt.query(-50.0, -50.0, 0.0, 0.0)?.is_empty());
+
+        // The vacated slot is reused along with a fresh payload.
+        let d = qt.insert(-40.0, -40.0, -30.0, -30.0, "d")?;
+        assert_eq!(d.index, a.index);
+        assert_eq!(qt.query(-50.0, -50.0, 0.0, 0.0)?, vec![(d, &"d")]);
+        Ok(())
+    }
+
+    #[test]
+    fn stale_handles() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        let a = qt.insert(-40.0, -40.0, -30.0, -30.0, "a")?;
+        assert!(qt.contains(a));
+        assert_eq!(qt.remove(a)?.1, "a");
+        assert!(!qt.contains(a));
+
+        // The slot of `a` gets reused, but under a new generation.
+        let b = qt.insert(-40.0, -40.0, -30.0, -30.0, "b")?;
+        assert_eq!(b.index, a.index);
+        assert_ne!(b.generation, a.generation);
+
+        // The stale handle does not resolve to, omit, or remove `b`.
+        assert_eq!(qt.get(a), None);
+        assert_eq!(qt.get_mut(a), None);
+        assert_eq!(qt.query_omit(-50.0, -50.0, 0.0, 0.0, Some(a))?, vec![(b, &"b")]);
+        assert_eq!(qt.remove(a), Err(QuadtreeError::EntityAlreadyRemoved));
+        assert_eq!(qt.get(b), Some(&"b"));
+
+        // Handles past every slot are not found at all, while any other
+        // generation of a slot is stale, since generations wrap around.
+        let unknown = EntityHandle {
+            index: 7,
+            generation: 0,
+        };
+        let other = EntityHandle {
+            generation: b.generation.wrapping_add(1),
+            ..b
+        };
+        assert_eq!(qt.remove(unknown), Err(QuadtreeError::EntityNotFound));
+        assert_eq!(qt.remove(other), Err(QuadtreeError::EntityAlreadyRemoved));
+        assert_eq!(qt.query(-50.0, -50.0, 0.0, 0.0)?, vec![(b, &"b")]);
+        Ok(())
+    }
+
+    #[test]
+    fn update() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        let a = qt.insert(-40.0, -40.0, -30.0, -30.0, "a")?;
+        let b = qt.insert(-20.0, -20.0, -10.0, -10.0, "b")?;
+        let c = qt.insert(10.0, 10.0, 20.0, 20.0, "c")?;
+
+        // Moving within the same leaves relinks nothing.
+        let mut tv = TestVisitor::new();
+        qt.traverse(&mut tv);
+        tv.assert_counts(3, 4, 1);
+        tv.reset();
+        let nodes = format!("{:?}", qt.nodes);
+        let entity_nodes = format!("{:?}", qt.entity_nodes);
+        let moved = Entity {
+            left: 12.0,
+            top: 12.0,
+            right: 22.0,
+            bottom: 22.0,
+        };
+        assert!(qt.same_home(qt.entities.get(c.index), &moved));
+        qt.update(c, 12.0, 12.0, 22.0, 22.0)?;
+        assert_eq!(format!("{:?}", qt.nodes), nodes);
+        assert_eq!(format!("{:?}", qt.entity_nodes), entity_nodes);
+        qt.traverse(&mut tv);
+        tv.assert_counts(3, 4, 1);
+        tv.reset();
+        assert_eq!(qt.query(21.0, 21.0, 25.0, 25.0)?, vec![(c, &"c")]);
+
+        // The same holds for a move that keeps an entity in its home node.
+        let mut loose = Quadtree::with_storage(0.0, 0.0, 100.0, 100.0, 1, Storage::Loose(2.0))?;
+        loose.insert(-40.0, -40.0, -30.0, -30.0, ())?;
+        let d = loose.insert(10.0, 10.0, 20.0, 20.0, ())?;
+        let nodes = format!("{:?}", loose.nodes);
+        let entity_nodes = format!("{:?}", loose.entity_nodes);
+        loose.update(d, 11.0, 11.0, 21.0, 21.0)?;
+        assert_eq!(format!("{:?}", loose.nodes), nodes);
+        assert_eq!(format!("{:?}", loose.entity_nodes), entity_nodes);
+        assert_eq!(ids(loose.query(20.5, 20.5, 21.0, 21.0)?), vec![d.index]);
+
+        // Moving across leaves keeps the handle, and the crowded leaf splits.
+        qt.update(c, -45.0, -20.0, -35.0, -15.0)?;
+        assert!(qt.query(0.0, 0.0, 50.0, 50.0)?.is_empty());
+        assert_eq!(qt.query(-50.0, -25.0, -40.0, -20.0)?, vec![(c, &"c")]);
+        assert_eq!(qt.get(c), Some(&"c"));
+        qt.traverse(&mut tv);
+        tv.assert_counts(3, 7, 2);
+        tv.reset();
+
+        // Growing into several leaves links it into each of them.
+        qt.update(a, -40.0, -40.0, 40.0, 40.0)?;
+        let mut q = ids(qt.query(30.0, 30.0, 35.0, 35.0)?);
+        q.sort();
+        assert_eq!(q, vec![a.index]);
+        let mut q = ids(qt.query(-50.0, -50.0, 0.0, 0.0)?);
+        q.sort();
+        assert_eq!(q, vec![a.index, b.index, c.index]);
+
+        // Invalid boxes and stale handles are rejected untouched.
+        assert_eq!(qt.update(b, 0.0, 0.0, -1.0, 1.0), Err(QuadtreeError::InsertHasInvertedBounds));
+        assert_eq!(qt.update(b, 0.0, 0.0, 60.0, 1.0), Err(QuadtreeError::InsertIsOutOfBounds));
+        qt.remove(b)?;
+        assert_eq!(qt.update(b, 0.0, 0.0, 1.0, 1.0), Err(QuadtreeError::EntityAlreadyRemoved));
+        Ok(())
+    }
+
+    #[test]
+    fn nearest() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::<&str, i32>::new(0, 0, 100, 100, 2)?;
+        assert!(qt.nearest(0, 0)?.is_none());
+
+        let big = qt.insert(-40, -40, 40, -30, "big")?;
+        let a = qt.insert(-45, 30, -35, 40, "a")?;
+        let b = qt.insert(10, 10, 20, 20, "b")?;
+        let c = qt.insert(35, 35, 45, 45, "c")?;
+
+        assert_eq!(qt.nearest(12, 15)?, Some((b, &"b", 0.0)));
+        assert_eq!(qt.nearest(-40, 20)?, Some((a, &"a", 10.0)));
+
+        // The large entity spans many leaves but is only reported once.
+        let near = qt.k_nearest(10, -20, 10)?;
+        assert_eq!(near.len(), 4);
+        assert_eq!(near[0], (big, &"big", 10.0));
+        assert_eq!(near[1], (b, &"b", 30.0));
+        assert_eq!(near[2].0, c);
+        assert_eq!(near[3].0, a);
+        assert!(near.windows(2).all(|w| w[0].2 <= w[1].2));
+
+        assert_eq!(qt.k_nearest(10, -20, 2)?.len(), 2);
+        assert!(qt.k_nearest(10, -20, 0)?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn query_circle() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        let big = qt.insert(-40.0, -40.0, 40.0, -30.0, "big")?;
+        let inside = qt.insert(-5.0, -5.0, 5.0, 5.0, "inside")?;
+        let edge = qt.insert(18.0, -2.0, 25.0, 2.0, "edge")?;
+        // In the bounding square of the circle, but outside the circle.
+        qt.insert(16.0, 16.0, 19.0, 19.0, "corner")?;
+        qt.insert(30.0, 30.0, 40.0, 40.0, "far")?;
+
+        let mut q = ids(qt.query_circle(0.0, 0.0, 20.0)?);
+        q.sort();
+        assert_eq!(q, vec![inside.index, edge.index]);
+        assert_eq!(qt.query(-20.0, -20.0, 20.0, 20.0)?.len(), 3);
+
+        // Touching counts as overlapping, like it does for boxes.
+        let mut q = ids(qt.query_circle(0.0, 0.0, 30.0)?);
+        q.sort();
+        assert_eq!(q, vec![big.index, inside.index, edge.index, 3]);
+
+        let mut q = ids(qt.query_circle_omit(0.0, 0.0, 30.0, Some(inside))?);
+        q.sort();
+        assert_eq!(q, vec![big.index, edge.index, 3]);
+
+        // A radius is a length, so it cannot be negative or NaN.
+        let negative = qt.query_circle(0.0, 0.0, -20.0);
+        assert!(negative.is_err_and(|e| e == QuadtreeError::NegativeRadius));
+        let mut ctx = QueryContext::new();
+        let mut out = Vec::new();
+        let nan = qt.query_circle_omit_into(&mut ctx, 0.0, 0.0, f64::NAN, None, &mut out);
+        assert!(nan.is_err_and(|e| e == QuadtreeError::NegativeRadius));
+        assert_eq!(ids(qt.query_circle(0.0, 0.0, 0.0)?), vec![inside.index]);
+        Ok(())
+    }
+
+    #[test]
+    fn raycast() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        let wall = qt.insert(30.0, -40.0, 35.0, 40.0, "wall")?;
+        let near = qt.insert(10.0, -2.0, 12.0, 2.0, "near")?;
+        let far = qt.insert(40.0, -5.0, 45.0, 5.0, "far")?;
+        qt.insert(10.0, 20.0, 12.0, 22.0, "off the ray")?;
+        qt.insert(-20.0, -2.0, -18.0, 2.0, "behind")?;
+
+        assert_eq!(qt.raycast(0.0, 0.0, 1.0, 0.0, 100.0)?, Some((near, &"near", 10.0)));
+        // Direction is normalized, and the origin inside an entity hits at 0.
+        assert_eq!(qt.raycast(11.0, 0.0, 5.0, 0.0, 100.0)?, Some((near, &"near", 0.0)));
+        assert_eq!(qt.raycast(0.0, 0.0, 1.0, 0.0, 5.0)?, None);
+        assert_eq!(qt.raycast(0.0, 0.0, 0.0, 0.0, 100.0)?, None);
+
+        let hits = qt.raycast_all(0.0, 0.0, 1.0, 0.0, 100.0)?;
+        assert_eq!(
+            hits,
+            vec![(near, &"near", 10.0), (wall, &"wall", 30.0), (far, &"far", 40.0)]
+        );
+        assert_eq!(qt.raycast_all(0.0, 0.0, 1.0, 0.0, 35.0)?.len(), 2);
+
+        // Diagonal rays only report what they actually cross.
+        let hits = qt.raycast_all(0.0, -48.0, 1.0, 1.0, 200.0)?;
+        assert_eq!(hits.len(), 2);
+        assert_eq!(hits[0].0, wall);
+        assert!((hits[0].2 - 30.0 * 2f64.sqrt()).abs() < 1e-9);
+        assert_eq!(hits[1].0, far);
+        assert!((hits[1].2 - 43.0 * 2f64.sqrt()).abs() < 1e-9);
+        Ok(())
+    }
+
+    #[test]
+    fn query_into() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        let mut ctx = QueryContext::new();
+        let mut out = Vec::new();
+        qt.query_into(&mut ctx, -50.0, -50.0, 50.0, 50.0, &mut out)?;
+        assert!(out.is_empty());
+
+        let big = qt.insert(-40.0, -40.0, 40.0, 40.0, ())?;
+        let a = qt.insert(-40.0, -40.0, -30.0, -30.0, ())?;
+        let b = qt.insert(30.0, 30.0, 40.0, 40.0, ())?;
+        let c = qt.insert(10.0, -20.0, 20.0, -10.0, ())?;
+
+        // The same context serves queries as the tree grows.
+        qt.query_into(&mut ctx, -50.0, -50.0, 0.0, 0.0, &mut out)?;
+        out.sort();
+        assert_eq!(out, vec![big, a]);
+        qt.query_into(&mut ctx, 0.0, -50.0, 50.0, 50.0, &mut out)?;
+        out.sort();
+        assert_eq!(out, vec![big, b, c]);
+
+        // Results match the allocating query, even across a stamp wrap.
+        ctx.stamp = u32::MAX - 1;
+        for _ in 0..3 {
+            qt.query_into(&mut ctx, -50.0, -50.0, 50.0, 50.0, &mut out)?;
+            let mut expected: Vec<_> = qt
+                .query(-50.0, -50.0, 50.0, 50.0)?
+                .into_iter()
+                .map(|(entity, _)| entity)
+                .collect();
+            out.sort();
+            expected.sort();
+            assert_eq!(out, expected);
+            assert_eq!(out.len(), 4);
+        }
+
+        // The other queries share the context and its dedupe stamps.
+        qt.query_omit_into(&mut ctx, -50.0, -50.0, 0.0, 0.0, Some(big), &mut out)?;
+        assert_eq!(out, vec![a]);
+        qt.query_circle_omit_into(&mut ctx, 35.0, 35.0, 5.0, Some(b), &mut out)?;
+        assert_eq!(out, vec![big]);
+        let mut hits = Vec::new();
+        qt.k_nearest_into(&mut ctx, 25.0, -15.0, 2, &mut hits)?;
+        assert_eq!(hits, vec![(big, 0.0), (c, 5.0)]);
+        qt.raycast_all_into(&mut ctx, -50.0, 35.0, 1.0, 0.0, 100.0, &mut hits)?;
+        assert_eq!(hits, vec![(big, 10.0), (b, 80.0)]);
+        Ok(())
+    }
+
+    #[test]
+    fn query_each_and_iter() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        assert!(!qt.any_in(-50.0, -50.0, 50.0, 50.0));
+
+        qt.insert(-40.0, -40.0, 40.0, 40.0, 0)?;
+        qt.insert(-40.0, -40.0, -30.0, -30.0, 1)?;
+        qt.insert(30.0, 30.0, 40.0, 40.0, 2)?;
+        qt.insert(10.0, -20.0, 20.0, -10.0, 3)?;
+        qt.insert(-25.0, 10.0, 25.0, 11.0, 4)?;
+        qt.insert(44.0, 44.0, 46.0, 46.0, 5)?;
+
+        // The iterator reports the same entities as the collecting query,
+        // each once, for queries touching and straddling leaf edges.
+        for (x1, y1, x2, y2) in [
+            (-50.0, -50.0, 50.0, 50.0),
+            (0.0, 0.0, 25.0, 25.0),
+            (-25.0, -25.0, 0.0, 0.0),
+            (-12.5, 10.0, 12.5, 10.5),
+            (42.0, 42.0, 50.0, 50.0),
+        ] {
+            let mut iter: Vec<_> = qt.query_iter(x1, y1, x2, y2).map(|(_, p)| *p).collect();
+            let mut query: Vec<_> = qt.query(x1, y1, x2, y2)?.into_iter().map(|(_, p)| *p).collect();
+            iter.sort();
+            query.sort();
+            assert_eq!(iter, query);
+        }
+
+        // Early exit hands back the break value.
+        let first_small = qt.query_each(-50.0, -50.0, 50.0, 50.0, |entity, payload| {
+            match *payload > 0 {
+                true => ControlFlow::Break(entity),
+                false => ControlFlow::Continue(()),
+            }
+        });
+        assert!(first_small.is_break());
+
+        let mut count = 0;
+        let flow = qt.query_each(0.0, 0.0, 50.0, 50.0, |_, _| -> ControlFlow<()> {
+            count += 1;
+            ControlFlow::Continue(())
+        });
+        assert!(flow.is_continue());
+        assert_eq!(count, 4);
+
+        assert!(qt.any_in(44.5, 44.5, 45.0, 45.0));
+        assert!(!qt.any_in(41.0, 41.0, 43.0, 43.0));
+        Ok(())
+    }
+
+    #[test]
+    fn collision_pairs() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::<(), i32>::new(0, 0, 100, 100, 2)?;
+        assert!(qt.collision_pairs().is_empty());
+
+        let big = qt.insert(-40, -40, 40, 40, ())?;
+        let a = qt.insert(-45, -45, -35, -35, ())?;
+        let b = qt.insert(-38, -38, -30, -30, ())?;
+        let c = qt.insert(20, 20, 30, 30, ())?;
+        // Touches `c` along the leaf edge at x = 30.
+        let d = qt.insert(30, 20, 45, 25, ())?;
+        // Straddles the center, overlapping `big` in all four quadrants.
+        let e = qt.insert(-5, -5, 5, 5, ())?;
+        qt.insert(44, -48, 48, -44, ())?;
+
+        let mut pairs = qt.collision_pairs();
+        pairs.sort();
+        let mut expected = vec![(big, a), (big, b), (big, c), (big, d), (big, e), (a, b), (c, d)];
+        expected.sort();
+        assert_eq!(pairs, expected);
+
+        // Matches running an omitting query for every entity.
+        let mut brute = Vec::new();
+        for entity in [big, a, b, c, d, e] {
+            for (other, _) in qt.query_omit(-50, -50, 50, 50, Some(entity))? {
+                let rect = qt.entities.get(entity.index);
+                let hit = qt.query_omit(rect.left, rect.top, rect.right, rect.bottom, Some(entity))?;
+                if entity < other && hit.iter().any(|(h, _)| *h == other) {
+                    brute.push((entity, other));
+                }
+            }
+        }
+        brute.sort();
+        assert_eq!(pairs, brute);
+        Ok(())
+    }
+
+    #[test]
+    fn loose_storage() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::with_storage(0.0, 0.0, 100.0, 100.0, 4, Storage::Loose(2.0))?;
+        let mut split = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        let rects = [
+            (-40.0, -40.0, 40.0, 40.0),
+            (-40.0, 30.0, -30.0, 40.0),
+            (-40.0, 10.0, -30.0, 20.0),
+            (-20.0, 30.0, -10.0, 40.0),
+            (-20.0, 10.0, -10.0, 20.0),
+            (30.0, 30.0, 40.0, 40.0),
+            (30.0, 10.0, 40.0, 20.0),
+            (10.0, 30.0, 20.0, 40.0),
+            (10.0, 10.0, 20.0, 20.0),
+            (-5.0, 5.0, -1.0, 9.0),
+        ];
+        let mut h = Vec::new();
+        for (x1, y1, x2, y2) in rects {
+            h.push(qt.insert(x1, y1, x2, y2, ())?);
+            split.insert(x1, y1, x2, y2, ())?;
+        }
+
         /***
+        The large entity fits in no child's loose bounds, so it stays on
+        the root branch. The small ones follow their centers down.
         |-----------------------|
-        | x x | x x |           |
-        |-----|-----|     x     |
-        | x x | x x |           |
-        |-----------|-----------|
         |           |           |
-        |     x     |     x     |
         |           |           |
+        |           |           |
+        |-----------x-----------|
+        | x   | x x |   x x     |
+        |-----|-----|           |
+        | x   | x   |   x x     |
         |-----------------------|
          */
-        qt.insert(-40.0, 30.0, -30.0, 40.0)?;
-        qt.insert(-40.0, 10.0, -30.0, 20.0)?;
-        qt.insert(-20.0, 30.0, -10.0, 40.0)?;
-        qt.insert(-20.0, 10.0, -10.0, 20.0)?;
+        let mut tv = TestVisitor::new();
         qt.traverse(&mut tv);
-        tv.assert_counts(11, 7, 2);
+        tv.assert_counts(10, 7, 2);
         tv.reset();
 
-        // NE
+        // Every kind of query finds the same entities as with split storage.
+        for (x1, y1, x2, y2) in [
+            (-50.0, -50.0, 50.0, 50.0),
+            (-39.0, 31.0, -38.0, 32.0),
+            (-12.0, 12.0, 12.0, 12.5),
+            (41.0, 41.0, 49.0, 49.0),
+            (45.0, 0.0, 49.0, 5.0),
+        ] {
+            let expected = sorted_ids(split.query(x1, y1, x2, y2)?);
+            assert_eq!(sorted_ids(qt.query(x1, y1, x2, y2)?), expected);
+            assert_eq!(sorted_ids(qt.query_iter(x1, y1, x2, y2)), expected);
+        }
+        assert_eq!(
+            sorted_ids(qt.query_circle(-3.0, 10.0, 5.0)?),
+            sorted_ids(split.query_circle(-3.0, 10.0, 5.0)?)
+        );
+        assert_eq!(qt.nearest(0.0, 47.0)?.map(|(e, _, d)| (e.index, d)), Some((0, 7.0)));
+        let hit = qt.raycast(-49.0, 35.0, 1.0, 0.0, 100.0)?;
+        assert_eq!(hit.map(|(e, _, d)| (e.index, d)), Some((0, 9.0)));
+        let mut pairs = qt.collision_pairs();
+        let mut expected = split.collision_pairs();
+        pairs.sort();
+        expected.sort();
+        assert_eq!(pairs, expected);
+
+        // Entities move between nodes and are removed from their one node.
+        qt.update(h[1], 30.0, -40.0, 40.0, -30.0)?;
+        assert_eq!(ids(qt.query(-39.0, 31.0, -38.0, 32.0)?), vec![0]);
+        assert_eq!(sorted_ids(qt.query(31.0, -39.0, 32.0, -38.0)?), vec![0, 1]);
+        for entity in &h[1..] {
+            assert!(qt.remove(*entity).is_ok());
+        }
+
+        // The large entity on the root branch survives the collapse.
+        qt.cleanup();
+        qt.traverse(&mut tv);
+        tv.assert_counts(1, 1, 0);
+        tv.reset();
+        assert_eq!(ids(qt.query(0.0, 0.0, 1.0, 1.0)?), vec![0]);
+        Ok(())
+    }
+
+    #[test]
+    fn enclosing_storage() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::with_storage(0.0, 0.0, 100.0, 100.0, 1, Storage::Enclosing)?;
+        // The large entity would make a split tree with such small leaves
+        // subdivide as deep as it can.
+        let mut split = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        let rects = [
+            (-40.0, -40.0, 40.0, 40.0),
+            (0.0, 10.0, 10.0, 20.0),
+            (-20.0, -20.0, -10.0, -10.0),
+            (10.0, 30.0, 20.0, 40.0),
+        ];
+        let mut h = Vec::new();
+        for (x1, y1, x2, y2) in rects {
+            h.push(qt.insert(x1, y1, x2, y2, ())?);
+            split.insert(x1, y1, x2, y2, ())?;
+        }
+
         /***
+        The large entity crosses the root's split lines and stays on the
+        root branch. The one touching the vertical split line from the
+        right is enclosed by the bottom right child.
         |-----------------------|
-        | x x | x x | x x | x x |
-        |-----|-----|-----|-----|
-        | x x | x x | x x | x x |
-        |-----------|-----------|
         |           |           |
-        |     x     |     x     |
+        |     x     |           |
         |           |           |
+        |-----------x-----|-----|
+        |           |x    |     |
+        |           |-----|-----|
+        |           | x   |     |
         |-----------------------|
          */
-        qt.insert(30.0, 30.0, 40.0, 40.0)?;
-        qt.insert(30.0, 10.0, 40.0, 20.0)?;
-        qt.insert(10.0, 30.0, 20.0, 40.0)?;
-        qt.insert(10.0, 10.0, 20.0, 20.0)?;
+        let mut tv = TestVisitor::new();
         qt.traverse(&mut tv);
-        tv.assert_counts(18, 10, 3);
+        tv.assert_counts(4, 7, 2);
         tv.reset();
 
-        // SW
-        /***
-        |-----------------------|
-        | x x | x x | x x | x x |
-        |-----|-----|-----|-----|
-        | x x | x x | x x | x x |
-        |-----|-----|-----------|
-        | x x | x x |           |
-        |-----|-----|     x     |
-        | x x | x x |           |
-        |-----------------------|
-         */
-        qt.insert(-40.0, -40.0, -30.0, -30.0)?;
-        qt.insert(-40.0, -20.0, -30.0, -10.0)?;
-        qt.insert(-20.0, -40.0, -10.0, -30.0)?;
-        qt.insert(-20.0, -20.0, -10.0, -10.0)?;
+        for (x1, y1, x2, y2) in [
+            (-50.0, -50.0, 50.0, 50.0),
+            (-5.0, 10.0, 0.0, 20.0),
+            (20.0, 40.0, 30.0, 45.0),
+            (41.0, -49.0, 49.0, 49.0),
+        ] {
+            let expected = sorted_ids(split.query(x1, y1, x2, y2)?);
+            assert_eq!(sorted_ids(qt.query(x1, y1, x2, y2)?), expected);
+            assert_eq!(sorted_ids(qt.query_iter(x1, y1, x2, y2)), expected);
+        }
+        let nearest = qt.nearest(45.0, 45.0)?.map(|(e, _, d)| (e.index, d));
+        assert_eq!(nearest, Some((0, 5.0 * 2f64.sqrt())));
+        let mut pairs = qt.collision_pairs();
+        let mut expected = split.collision_pairs();
+        pairs.sort();
+        expected.sort();
+        assert_eq!(pairs, expected);
+
+        // Moving an entity across a split line lifts it to the branch.
+        qt.update(h[2], -5.0, -20.0, 5.0, -10.0)?;
+        assert_eq!(sorted_ids(qt.query(4.0, -15.0, 6.0, -14.0)?), vec![0, 2]);
+        qt.remove(h[1])?;
+        qt.remove(h[3])?;
+
+        // The root still holds two entities, one of them its own.
+        qt.cleanup();
         qt.traverse(&mut tv);
-        tv.assert_counts(25, 13, 4);
+        tv.assert_counts(2, 4, 1);
         tv.reset();
+        Ok(())
+    }
+
+    #[test]
+    fn auto_grow() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::<(), i32>::new(0, 0, 100, 100, 4)?;
+        let mut h = vec![
+            qt.insert(-40, -40, -30, -30, ())?,
+            qt.insert(30, -40, 40, -30, ())?,
+            qt.insert(-40, 30, -30, 40, ())?,
+            qt.insert(30, 30, 40, 40, ())?,
+            qt.insert(-10, -10, 10, 10, ())?,
+        ];
+        assert_eq!(
+            qt.insert(60, 10, 70, 20, ()),
+            Err(QuadtreeError::InsertIsOutOfBounds)
+        );
+        let old_root = *qt.nodes.get(qt.root.idx);
+        assert_eq!(qt.max_depth, 6);
 
-        // SE
         /***
-        |-----------------------|
-        | x x | x x | x x | x x |
-        |-----|-----|-----|-----|
-        | x x | x x | x x | x x |
-        |-----|-----|-----|-----|
-        | x x | x x | x x | x x |
-        |-----|-----|-----|-----|
-        | x x | x x | x x | x x |
-        |-----------------------|
+        Growing to the right and down makes the old root the top left
+        quadrant of a root twice its size.
+        |-----------------------|-----------------------|
+        |  x  |  x  |           |                       |
+        |-----x-----|           |                       |
+        |  x  |  x  |           |                       |
+        |-----------|-----------|           x           |
+        |           |           |                       |
+        |           |           |                       |
+        |           |           |                       |
+        |-----------------------|-----------------------|
          */
-        qt.insert(30.0, -40.0, 40.0, -30.0)?;
-        qt.insert(30.0, -20.0, 40.0, -10.0)?;
-        qt.insert(10.0, -40.0, 20.0, -30.0)?;
-        qt.insert(10.0, -20.0, 20.0, -10.0)?;
+        qt.set_auto_grow(true)?;
+        h.push(qt.insert(60, 10, 70, 20, ())?);
+        assert_eq!(qt.root.region.left, -50);
+        assert_eq!(qt.root.region.right, 150);
+        assert_eq!(qt.max_depth, 7);
+        let fc = qt.nodes.get(qt.root.idx).first_child.unwrap();
+        assert_eq!(qt.nodes.get(fc).first_child, old_root.first_child);
+
+        let mut tv = TestVisitor::new();
         qt.traverse(&mut tv);
-        tv.assert_counts(32, 16, 5);
+        tv.assert_counts(9, 7, 2);
         tv.reset();
+
+        // Growing several times, and in the other directions, as needed.
+        h.push(qt.insert(-500, -500, -490, -490, ())?);
+        qt.update(h[0], 900, 900, 910, 910)?;
+        assert!(qt.root.region.left <= -500 && qt.root.region.right >= 910);
+        assert!(h.iter().all(|entity| qt.contains(*entity)));
+        let mut all = ids(qt.query(-500, -500, 910, 910)?);
+        all.sort();
+        assert_eq!(all, vec![0, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(ids(qt.query(899, 899, 900, 900)?), vec![0]);
+
+        // Inverted boxes are still rejected without growing the root.
+        let root = qt.root.region;
+        assert_eq!(
+            qt.insert(5000, 0, 4000, 10, ()),
+            Err(QuadtreeError::InsertHasInvertedBounds)
+        );
+        assert_eq!(qt.root.region.right, root.right);
         Ok(())
     }
 
     #[test]
-    fn insert_error_handling() -> Result<(), QuadtreeError> {
-        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4);
+    fn wrap() -> Result<(), QuadtreeError> {
+        let loose_storage = Storage::Loose(2.0);
+        let mut loose = Quadtree::<(), f32>::with_storage(0.0, 0.0, 100.0, 100.0, 4, loose_storage)?;
+        assert_eq!(loose.set_wrap(true), Err(QuadtreeError::WrapRequiresSplitStorage));
+        let mut growing = Quadtree::<(), f32>::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        growing.set_auto_grow(true)?;
+        assert_eq!(growing.set_wrap(true), Err(QuadtreeError::WrapWithAutoGrow));
+        growing.set_auto_grow(false)?;
+        growing.insert(0.0, 0.0, 1.0, 1.0, ())?;
+        assert_eq!(growing.set_wrap(true), Err(QuadtreeError::WrapOnNonEmptyTree));
+
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        qt.set_wrap(true)?;
+        assert_eq!(qt.set_auto_grow(true), Err(QuadtreeError::WrapWithAutoGrow));
 
-        // entity box checks
-        let x2_less_than_x1 = qt.insert(1.0, 1.0, -1.0, 2.0);
-        let x2_same_as_x1 = qt.insert(1.0, 1.0, 1.0, 2.0);
-        let y2_less_than_y1 = qt.insert(1.0, 1.0, 2.0, -1.0);
-        let y2_same_as_y1 = qt.insert(1.0, 1.0, 2.0, 1.0);
-        assert!(x2_less_than_x1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
-        assert!(x2_same_as_x1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
-        assert!(y2_less_than_y1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
-        assert!(y2_same_as_y1.is_err_and(|e| e == QuadtreeError::InsertHasInvertedBounds));
+        /***
+        Boxes crossing the edges continue on the opposite side, and boxes
+        outside of the root are moved into it.
+        |-----------------------|
+        |c                     c|
+        |                       |
+        |                       |
+        |a  b       de         a|
+        |                      f|
+        |                       |
+        |c                     c|
+        |-----------------------|
+         */
+        let a = qt.insert(40.0, -10.0, 60.0, 10.0, ())?;
+        let b = qt.insert(-45.0, -5.0, -42.0, 5.0, ())?;
+        let c = qt.insert(45.0, 45.0, 55.0, 55.0, ())?;
+        let d = qt.insert(0.0, 0.0, 5.0, 5.0, ())?;
+        let e = qt.insert(-300.0, 0.0, -295.0, 5.0, ())?;
+        let f = qt.insert(47.0, 20.0, 49.0, 22.0, ())?;
+        assert_eq!(qt.root.region.right, 50.0);
+
+        let mut ctx = QueryContext::new();
+        let mut out = Vec::new();
+        for ((x1, y1, x2, y2), expected) in [
+            ((45.0, -5.0, 55.0, 5.0), vec![a.index, b.index]),
+            ((-48.0, -48.0, -46.0, -46.0), vec![c.index]),
+            ((-200.0, -3.0, -190.0, 3.0), vec![d.index, e.index]),
+            ((-60.0, -60.0, 60.0, 60.0), vec![0, 1, 2, 3, 4, 5]),
+        ] {
+            assert_eq!(sorted_ids(qt.query(x1, y1, x2, y2)?), expected);
+            assert_eq!(sorted_ids(qt.query_iter(x1, y1, x2, y2)), expected);
+            qt.query_into(&mut ctx, x1, y1, x2, y2, &mut out)?;
+            assert_eq!(sorted_ids(out.iter().map(|&e| (e, ()))), expected);
+        }
 
-        // out of bounds checks
-        let x1_out_of_bounds = qt.insert(-51.0, 0.0, 0.0, 1.0);
-        let x2_out_of_bounds = qt.insert(0.0, 0.0, 51.0, 1.0);
-        let y1_out_of_bounds = qt.insert(0.0, -51.0, 1.0, 0.0);
-        let y2_out_of_bounds = qt.insert(0.0, 0.0, 1.0, 51.0);
-        assert!(x1_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
-        assert!(x2_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
-        assert!(y1_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
-        assert!(y2_out_of_bounds.is_err_and(|e| e == QuadtreeError::InsertIsOutOfBounds));
+        // Distances are measured the short way around.
+        assert_eq!(qt.nearest(-49.0, 21.0)?.map(|(e, _, d)| (e, d)), Some((f, 2.0)));
+        assert_eq!(qt.nearest(-48.0, -40.0)?.map(|(e, _, d)| (e, d)), Some((c, 5.0)));
+        assert_eq!(ids(qt.query_circle(-49.0, 21.0, 3.0)?), vec![f.index]);
+        let hit = qt.raycast(-50.0, -8.0, 1.0, 0.0, 100.0)?;
+        assert_eq!(hit.map(|(e, _, d)| (e, d)), Some((a, 0.0)));
+
+        let mut pairs = qt.collision_pairs();
+        pairs.sort();
+        assert_eq!(pairs, vec![(a, b), (d, e)]);
+
+        // Splitting a leaf holding part of a wrapped entity only moves that
+        // part into the new leaves.
+        let mut small = Vec::new();
+        for (x, y) in [(10.0, -40.0), (20.0, -40.0), (10.0, -30.0), (20.0, -30.0)] {
+            small.push(qt.insert(x, y, x + 2.0, y + 2.0, ())?);
+        }
+        assert_eq!(sorted_ids(qt.query(-50.0, -10.0, -49.0, 10.0)?), vec![a.index]);
 
+        qt.remove(a)?;
+        assert_eq!(ids(qt.query(45.0, -5.0, 55.0, 5.0)?), vec![b.index]);
+        qt.update(c, 0.0, 90.0, 10.0, 110.0)?;
+        assert_eq!(sorted_ids(qt.query(0.0, 0.0, 1.0, 1.0)?), vec![2, 3, 4]);
+        assert!(qt.query(-48.0, -48.0, -46.0, -46.0)?.is_empty());
+
+        // Every part of every entity is unlinked on removal.
+        for entity in [b, c, d, e, f].into_iter().chain(small) {
+            qt.remove(entity)?;
+        }
+        qt.cleanup();
+        let mut tv = TestVisitor::new();
+        qt.traverse(&mut tv);
+        tv.assert_counts(0, 1, 0);
         Ok(())
     }
 
     #[test]
-    fn query_and_omit() -> Result<(), QuadtreeError> {
-        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4);
-
-        // Cover almost entire quadtree root
-        let entity = qt.insert(-40.0, -40.0, 40.0, 40.0)?;
-        let q = qt.query(-10.0, -10.0, 10.0, 10.0);
-        assert!(q.contains(&0));
+    fn wrap_extreme_bounds() -> Result<(), QuadtreeError> {
+        // The root spans -1e9..1e9, so a wrapped span can end well past
+        // i32::MAX before it is folded back into the root.
+        let mut qt = QuadtreeBuilder::new(0, 0, 2_000_000_000, 2_000_000_000)
+            .max_entities(1)
+            .wrap(true)
+            .build::<()>()?;
+        let edge = qt.insert(999_999_990, 0, 1_000_000_010, 10, ())?;
+        let center = qt.insert(0, 0, 10, 10, ())?;
+        qt.insert(600_000_000, 0, 600_000_010, 10, ())?;
+
+        let mut found = ids(qt.query(-1_000_000_001, 0, 499_999_999, 10)?);
+        found.sort();
+        assert_eq!(found, vec![edge.index, center.index]);
+        let iter_found = qt.query_iter(-1_000_000_001, 0, 499_999_999, 10).count();
+        assert_eq!(iter_found, 2);
+
+        // Spans that cannot be measured at all are still rejected.
+        let too_wide = qt.query(i32::MIN, 0, i32::MAX, 10);
+        assert!(too_wide.is_err_and(|e| e == QuadtreeError::CoordinateOverflow));
+        assert_eq!(qt.query_iter(i32::MIN, 0, i32::MAX, 10).count(), 0);
+        Ok(())
+    }
 
-        // NW
-        qt.insert(-40.0, 30.0, -30.0, 40.0)?;
-        qt.insert(-40.0, 10.0, -30.0, 20.0)?;
-        qt.insert(-20.0, 30.0, -10.0, 40.0)?;
-        qt.insert(-20.0, 10.0, -10.0, 20.0)?;
-        let q = qt.query(-50.0, 0.0, 0.0, 50.0);
-        let q_omit = qt.query_omit(-50.0, 0.0, 0.0, 50.0, Some(entity));
-        assert_eq!(q.len(), 5);
-        assert!(q.contains(&0));
-        assert!(q.contains(&1));
-        assert!(q.contains(&2));
-        assert!(q.contains(&3));
-        assert!(q.contains(&4));
-        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+    #[test]
+    fn cleanup_underpopulated() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+        let big = qt.insert(-40.0, -40.0, 40.0, 40.0, ())?;
+        let mut small = Vec::new();
+        for (x, y) in [(-40.0, 30.0), (-40.0, 10.0), (-20.0, 30.0), (-20.0, 10.0)] {
+            small.push(qt.insert(x, y, x + 10.0, y + 10.0, ())?);
+        }
+        for (x, y) in [(30.0, 30.0), (30.0, 10.0), (10.0, 30.0), (10.0, 10.0)] {
+            small.push(qt.insert(x, y, x + 10.0, y + 10.0, ())?);
+        }
+        let mut tv = TestVisitor::new();
+        qt.traverse(&mut tv);
+        tv.assert_counts(18, 10, 3);
+        tv.reset();
 
-        // NE
-        qt.insert(30.0, 30.0, 40.0, 40.0)?;
-        qt.insert(30.0, 10.0, 40.0, 20.0)?;
-        qt.insert(10.0, 30.0, 20.0, 40.0)?;
-        qt.insert(10.0, 10.0, 20.0, 20.0)?;
-        let q = qt.query(0.0, 0.0, 50.0, 50.0);
-        let q_omit = qt.query_omit(0.0, 0.0, 50.0, 50.0, Some(entity));
-        assert_eq!(q.len(), 5);
-        assert!(q.contains(&0));
-        assert!(q.contains(&5));
-        assert!(q.contains(&6));
-        assert!(q.contains(&7));
-        assert!(q.contains(&8));
-        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+        // Crowded branches are left alone.
+        qt.cleanup();
+        qt.traverse(&mut tv);
+        tv.assert_counts(18, 10, 3);
+        tv.reset();
 
-        // SW
-        qt.insert(-40.0, -40.0, -30.0, -30.0)?;
-        qt.insert(-40.0, -20.0, -30.0, -10.0)?;
-        qt.insert(-20.0, -40.0, -10.0, -30.0)?;
-        qt.insert(-20.0, -20.0, -10.0, -10.0)?;
-        let q = qt.query(-50.0, -50.0, 0.0, 0.0);
-        let q_omit = qt.query_omit(-50.0, -50.0, 0.0, 0.0, Some(entity));
-        assert_eq!(q.len(), 5);
-        assert!(q.contains(&0));
-        assert!(q.contains(&9));
-        assert!(q.contains(&10));
-        assert!(q.contains(&11));
-        assert!(q.contains(&12));
-        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+        // Leaving the large entity plus two small ones in a branch lets it
+        // collapse, even though none of its leaves are empty.
+        for entity in &small[4..6] {
+            qt.remove(*entity)?;
+        }
+        qt.cleanup();
+        qt.traverse(&mut tv);
+        tv.assert_counts(13, 7, 2);
+        tv.reset();
 
-        // SE
-        qt.insert(30.0, -40.0, 40.0, -30.0)?;
-        qt.insert(30.0, -20.0, 40.0, -10.0)?;
-        qt.insert(10.0, -40.0, 20.0, -30.0)?;
-        qt.insert(10.0, -20.0, 20.0, -10.0)?;
-        let q = qt.query(0.0, -50.0, 50.0, 0.0);
-        let q_omit = qt.query_omit(0.0, -50.0, 50.0, 0.0, Some(entity));
-        assert_eq!(q.len(), 5);
-        assert!(q.contains(&0));
-        assert!(q.contains(&13));
-        assert!(q.contains(&14));
-        assert!(q.contains(&15));
-        assert!(q.contains(&16));
-        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+        // Collapsing a branch lets its parent collapse in the same pass,
+        // linking each entity once.
+        for entity in &small[..4] {
+            qt.remove(*entity)?;
+        }
+        qt.cleanup();
+        qt.traverse(&mut tv);
+        tv.assert_counts(3, 1, 0);
+        tv.reset();
 
-        // Center
-        let q = qt.query(-10.0, -10.0, 10.0, 10.0);
-        let q_omit = qt.query_omit(-10.0, -10.0, 10.0, 10.0, Some(entity));
-        assert_eq!(q.len(), 5);
-        assert!(q.contains(&0));
-        assert!(q.contains(&4));
-        assert!(q.contains(&8));
-        assert!(q.contains(&12));
-        assert!(q.contains(&16));
-        assert!(!q_omit.contains(&0) && q_omit.len() == 4);
+        let mut q = ids(qt.query(-50.0, -50.0, 50.0, 50.0)?);
+        q.sort();
+        assert_eq!(q, vec![big.index, small[6].index, small[7].index]);
         Ok(())
     }
 
     #[test]
-    fn remove_and_cleanup() -> Result<(), QuadtreeError> {
-        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4);
+    fn cleanup_step() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 1)?;
+        let mut entities = Vec::new();
+        for (x, y) in [(-40.0, -40.0), (-30.0, -40.0), (-40.0, -30.0), (30.0, 30.0)] {
+            entities.push(qt.insert(x, y, x + 5.0, y + 5.0, ())?);
+        }
+        let mut tv = TestVisitor::new();
+        qt.traverse(&mut tv);
+        let branches = tv.branches.len();
+        assert!(branches > 1);
+        tv.reset();
 
-        // Populate the quadtree
-        // Large centered entity
-        qt.insert(-40.0, -40.0, 40.0, 40.0)?; // 0
+        // Nothing to collapse, but a pass still has to visit the tree.
+        assert!(!qt.cleanup_step(1));
+        assert!(qt.cleanup_step(usize::MAX));
 
-        // NW
-        qt.insert(-40.0, 30.0, -30.0, 40.0)?; // 1
-        qt.insert(-40.0, 10.0, -30.0, 20.0)?; // 2
-        qt.insert(-20.0, 30.0, -10.0, 40.0)?; // 3
-        qt.insert(-20.0, 10.0, -10.0, 20.0)?; // 4
+        for entity in &entities[..3] {
+            qt.remove(*entity)?;
+        }
 
-        // NE
-        qt.insert(30.0, 30.0, 40.0, 40.0)?; // 5
-        qt.insert(30.0, 10.0, 40.0, 20.0)?; // 6
-        qt.insert(10.0, 30.0, 20.0, 40.0)?; // 7
-        qt.insert(10.0, 10.0, 20.0, 20.0)?; // 8
+        // Small budgets make progress across calls until the pass is done.
+        let mut steps = 1;
+        while !qt.cleanup_step(2) {
+            steps += 1;
+            // Changes between steps are picked up by the unfinished pass.
+            if steps == 3 {
+                qt.remove(entities[3])?;
+            }
+        }
+        assert!(steps > 3);
+        qt.traverse(&mut tv);
+        tv.assert_counts(0, 1, 0);
+        tv.reset();
 
-        // SW
-        qt.insert(-40.0, -40.0, -30.0, -30.0)?; // 9
-        qt.insert(-40.0, -20.0, -30.0, -10.0)?; // 10
-        qt.insert(-20.0, -40.0, -10.0, -30.0)?; // 11
-        qt.insert(-20.0, -20.0, -10.0, -10.0)?; // 12
+        // Splits between steps do not confuse a resumed pass.
+        for (x, y) in [(-40.0, -40.0), (-30.0, -40.0), (30.0, 30.0)] {
+            qt.insert(x, y, x + 5.0, y + 5.0, ())?;
+        }
+        assert!(!qt.cleanup_step(3));
+        let last = qt.insert(-40.0, -30.0, -35.0, -25.0, ())?;
+        while !qt.cleanup_step(3) {}
+        qt.remove(last)?;
+        assert_eq!(qt.query(-50.0, -50.0, 50.0, 50.0)?.len(), 3);
+        Ok(())
+    }
 
-        // SE
-        qt.insert(30.0, -40.0, 40.0, -30.0)?; // 13
-        qt.insert(30.0, -20.0, 40.0, -10.0)?; // 14
-        qt.insert(10.0, -40.0, 20.0, -30.0)?; // 15
-        qt.insert(10.0, -20.0, 20.0, -10.0)?; // 16
+    #[test]
+    fn remove_and_cleanup() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 4)?;
+
+        // Populate the quadtree
+        let h = [
+            // Large centered entity
+            qt.insert(-40.0, -40.0, 40.0, 40.0, ())?, // 0
+
+            // NW
+            qt.insert(-40.0, 30.0, -30.0, 40.0, ())?, // 1
+            qt.insert(-40.0, 10.0, -30.0, 20.0, ())?, // 2
+            qt.insert(-20.0, 30.0, -10.0, 40.0, ())?, // 3
+            qt.insert(-20.0, 10.0, -10.0, 20.0, ())?, // 4
+
+            // NE
+            qt.insert(30.0, 30.0, 40.0, 40.0, ())?, // 5
+            qt.insert(30.0, 10.0, 40.0, 20.0, ())?, // 6
+            qt.insert(10.0, 30.0, 20.0, 40.0, ())?, // 7
+            qt.insert(10.0, 10.0, 20.0, 20.0, ())?, // 8
+
+            // SW
+            qt.insert(-40.0, -40.0, -30.0, -30.0, ())?, // 9
+            qt.insert(-40.0, -20.0, -30.0, -10.0, ())?, // 10
+            qt.insert(-20.0, -40.0, -10.0, -30.0, ())?, // 11
+            qt.insert(-20.0, -20.0, -10.0, -10.0, ())?, // 12
+
+            // SE
+            qt.insert(30.0, -40.0, 40.0, -30.0, ())?, // 13
+            qt.insert(30.0, -20.0, 40.0, -10.0, ())?, // 14
+            qt.insert(10.0, -40.0, 20.0, -30.0, ())?, // 15
+            qt.insert(10.0, -20.0, 20.0, -10.0, ())?, // 16
+        ];
 
         /***
         Starting quadtree
@@ -958,13 +3550,10 @@ mod tests {
         tv.reset();
 
         /***
-        Because we only clean up empty leaves/branches,
-        the entity with id 0 will still remain in the
-        leaves where we removed child elements. There is
-        definitely room for improvement here where we could
-        track the number of unique elements and collapse
-        a branch if the # of unique is less than
-        max_entities_per_region. Maybe in the future.
+        Removing entities does not restructure the tree,
+        so the entity with id 0 will still remain in the
+        leaves where we removed child elements until
+        cleanup collapses the underpopulated branches.
         |-----------------------|
         | x x | x x | x x | x x |
         |-----|-----|-----|-----|
@@ -975,10 +3564,10 @@ mod tests {
         | x x | x x |  x  |  x  |
         |-----------------------|
          */
-        qt.remove(16);
-        qt.remove(15);
-        qt.remove(14);
-        qt.remove(13);
+        qt.remove(h[16])?;
+        qt.remove(h[15])?;
+        qt.remove(h[14])?;
+        qt.remove(h[13])?;
         qt.traverse(&mut tv);
         tv.assert_counts(28, 16, 5);
         tv.reset();
@@ -994,10 +3583,10 @@ mod tests {
         |  x  |  x  |  x  |  x  |
         |-----------------------|
          */
-        qt.remove(12);
-        qt.remove(11);
-        qt.remove(10);
-        qt.remove(9);
+        qt.remove(h[12])?;
+        qt.remove(h[11])?;
+        qt.remove(h[10])?;
+        qt.remove(h[9])?;
         qt.traverse(&mut tv);
         tv.assert_counts(24, 16, 5);
         tv.reset();
@@ -1013,10 +3602,10 @@ mod tests {
         |  x  |  x  |  x  |  x  |
         |-----------------------|
          */
-        qt.remove(8);
-        qt.remove(7);
-        qt.remove(6);
-        qt.remove(5);
+        qt.remove(h[8])?;
+        qt.remove(h[7])?;
+        qt.remove(h[6])?;
+        qt.remove(h[5])?;
         qt.traverse(&mut tv);
         tv.assert_counts(20, 16, 5);
         tv.reset();
@@ -1032,10 +3621,10 @@ mod tests {
         |  x  |  x  |  x  |  x  |
         |-----------------------|
          */
-        qt.remove(4);
-        qt.remove(3);
-        qt.remove(2);
-        qt.remove(1);
+        qt.remove(h[4])?;
+        qt.remove(h[3])?;
+        qt.remove(h[2])?;
+        qt.remove(h[1])?;
         qt.traverse(&mut tv);
         tv.assert_counts(16, 16, 5);
         tv.reset();
@@ -1051,35 +3640,287 @@ mod tests {
         |     |     |     |     |
         |-----------------------|
          */
-        qt.remove(0);
+        qt.remove(h[0])?;
         qt.traverse(&mut tv);
         tv.assert_counts(0, 16, 5);
         tv.reset();
 
         /***
-        First stage of cleanup
+        Merges cascade upward, so a single cleanup collapses
+        the empty leaves and then the root branch
         |-----------------------|
-        |           |           |
-        |           |           |
-        |           |           |
-        |-----------|-----------|
-        |           |           |
-        |           |           |
-        |           |           |
+        |                       |
+        |                       |
+        |                       |
+        |                       |
+        |                       |
+        |                       |
+        |                       |
         |-----------------------|
          */
         qt.cleanup();
         qt.traverse(&mut tv);
-        tv.assert_counts(0, 4, 1);
+        tv.assert_counts(0, 1, 0);
         tv.reset();
+        Ok(())
+    }
+
+    #[test]
+    fn bulk_load() -> Result<(), QuadtreeError> {
+        // A deterministic scatter of small and large boxes.
+        let mut next = lcg(7);
+        let mut rects = Vec::new();
+        for i in 0..300 {
+            let size = if i % 25 == 0 { 40 } else { 1 + next(6) as i32 };
+            let x = next(200) as i32 - 100;
+            let y = next(200) as i32 - 100;
+            rects.push((x.min(100 - size), y.min(100 - size), size, i));
+        }
+        let boxes = rects.iter().map(|&(x, y, size, i)| (x, y, x + size, y + size, i));
+
+        for storage in [Storage::Split, Storage::Loose(2.0), Storage::Enclosing] {
+            let mut qt = Quadtree::<usize, i32>::with_storage(0, 0, 200, 200, 4, storage)?;
+            let mut incremental = Quadtree::<usize, i32>::with_storage(0, 0, 200, 200, 4, storage)?;
+
+            // Half of the boxes go into an empty tree, the rest into one
+            // that already has structure.
+            let (first, second) = rects.split_at(150);
+            for part in [first, second] {
+                let handles =
+                    qt.extend(part.iter().map(|&(x, y, size, i)| (x, y, x + size, y + size, i)))?;
+                for (&(x, y, size, i), handle) in part.iter().zip(handles) {
+                    assert_eq!(qt.get(handle), Some(&i));
+                    incremental.insert(x, y, x + size, y + size, i)?;
+                }
+            }
+
+            // Both trees end up with the same shape and answers, though
+            // the entities are stored in a different order.
+            let mut tv = TestVisitor::new();
+            let mut tv_incremental = TestVisitor::new();
+            qt.traverse(&mut tv);
+            incremental.traverse(&mut tv_incremental);
+            for v in [&mut tv, &mut tv_incremental] {
+                v.leaves.sort();
+                v.branches.sort();
+            }
+            assert_eq!(tv.entities.len(), tv_incremental.entities.len());
+            assert_eq!(tv.leaves, tv_incremental.leaves);
+            assert_eq!(tv.branches, tv_incremental.branches);
+            let payloads = |results: Vec<(EntityHandle, &usize)>| {
+                let mut payloads: Vec<usize> = results.into_iter().map(|(_, &i)| i).collect();
+                payloads.sort();
+                payloads
+            };
+            for (x, y) in [(-90, -90), (-10, 20), (35, -60), (70, 70)] {
+                let found = payloads(qt.query(x, y, x + 25, y + 25)?);
+                let expected = payloads(incremental.query(x, y, x + 25, y + 25)?);
+                assert_eq!(found, expected);
+            }
+        }
+
+        let (qt, handles) = Quadtree::from_iter(0, 0, 200, 200, 4, boxes)?;
+        assert_eq!(handles.len(), 300);
+        assert_eq!(qt.get(handles[299]), Some(&299));
+
+        // A rejected box leaves the tree untouched.
+        let mut qt = Quadtree::<usize, i32>::new(0, 0, 200, 200, 4)?;
+        assert_eq!(
+            qt.extend([(0, 0, 5, 5, 0), (90, 90, 110, 110, 1)]),
+            Err(QuadtreeError::InsertIsOutOfBounds)
+        );
+        assert!(qt.query(-100, -100, 100, 100)?.is_empty());
+
+        // Entities are stored in Morton order, handles come back in input order.
+        let handles = qt.extend([(90, 90, 95, 95, 0), (-95, -95, -90, -90, 1)])?;
+        assert_eq!((handles[0].index, handles[1].index), (1, 0));
+        assert_eq!((qt.get(handles[0]), qt.get(handles[1])), (Some(&0), Some(&1)));
+
+        // A growing root grows once to fit every box, or not at all if
+        // any of them is rejected.
+        let mut qt = Quadtree::<usize, i32>::new(0, 0, 200, 200, 4)?;
+        qt.set_auto_grow(true)?;
+        qt.insert(0, 0, 5, 5, 0)?;
+        let nodes = format!("{:?}", qt.nodes);
+        for (rects, error) in [
+            (
+                vec![(300, 300, 310, 310, 1), (20, 20, 10, 30, 2)],
+                QuadtreeError::InsertHasInvertedBounds,
+            ),
+            (
+                vec![(300, 300, 310, 310, 1), (i32::MAX - 10, 0, i32::MAX, 5, 2)],
+                QuadtreeError::CoordinateOverflow,
+            ),
+        ] {
+            assert_eq!(qt.extend(rects), Err(error));
+            assert_eq!((qt.root.region.left, qt.root.region.right), (-100, 100));
+            assert_eq!((qt.root.region.top, qt.root.region.bottom), (-100, 100));
+            assert_eq!(format!("{:?}", qt.nodes), nodes);
+            assert_eq!(qt.query(-100, -100, 100, 100)?.len(), 1);
+        }
+        let handles = qt.extend([(300, 300, 310, 310, 1), (-400, 20, -390, 30, 2)])?;
+        assert_eq!((qt.root.region.left, qt.root.region.right), (-700, 900));
+        assert_eq!(qt.query(-700, -700, 900, 900)?.len(), 3);
+        assert_eq!(qt.get(handles[1]), Some(&2));
+        Ok(())
+    }
+
+    #[test]
+    fn validate_after_mutations() -> Result<(), QuadtreeError> {
+        let mut next = lcg(11);
+        let configs = [
+            (Storage::Split, false, false),
+            (Storage::Split, true, false),
+            (Storage::Split, false, true),
+            (Storage::Loose(1.5), false, false),
+            (Storage::Enclosing, true, false),
+        ];
+        for (storage, auto_grow, wrap) in configs {
+            let mut qt = QuadtreeBuilder::new(0, 0, 128, 128)
+                .max_entities(3)
+                .storage(storage)
+                .auto_grow(auto_grow)
+                .wrap(wrap)
+                .build::<u32>()?;
+            let mut live = Vec::new();
+            for step in 0..250 {
+                let size = 1 + next(if step % 10 == 0 { 50 } else { 8 }) as i32;
+                let mut x = next(120) as i32 - 60;
+                let mut y = next(120) as i32 - 60;
+                if !auto_grow && !wrap {
+                    x = x.min(64 - size);
+                    y = y.min(64 - size);
+                }
+                match next(6) {
+                    0 | 1 => live.push(qt.insert(x, y, x + size, y + size, step)?),
+                    2 if !live.is_empty() => {
+                        let entity = live.swap_remove(next(live.len() as u32) as usize);
+                        qt.remove(entity)?;
+                    }
+                    3 if !live.is_empty() => {
+                        let entity = live[next(live.len() as u32) as usize];
+                        qt.update(entity, x, y, x + size, y + size)?;
+                    }
+                    4 => live.extend(qt.extend([(x, y, x + size, y + size, step)])?),
+                    _ => {
+                        qt.cleanup_step(5);
+                    }
+                }
+                assert_eq!(qt.validate(), Ok(()), "{storage:?} after step {step}");
+            }
+            qt.cleanup();
+            assert_eq!(qt.validate(), Ok(()));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn validate_detects_corruption() -> Result<(), QuadtreeError> {
+        let build = || -> Result<Quadtree<(), f32>, QuadtreeError> {
+            let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 1)?;
+            qt.insert(-40.0, -40.0, -30.0, -30.0, ())?;
+            qt.insert(10.0, 10.0, 20.0, 20.0, ())?;
+            assert_eq!(qt.validate(), Ok(()));
+            Ok(qt)
+        };
+
+        // An entity erased without being unlinked.
+        let mut qt = build()?;
+        qt.entities.erase(1);
+        assert!(matches!(qt.validate(), Err(IntegrityError::VacantEntity { entity: 1, .. })));
+
+        // A stale count.
+        let mut qt = build()?;
+        let leaf = qt.nodes.get(0).first_child.unwrap();
+        qt.nodes.get_mut(leaf).num_entities += 1;
+        assert_eq!(
+            qt.validate(),
+            Err(IntegrityError::EntityCountMismatch {
+                node: leaf,
+                counted: 2,
+                linked: 1,
+            })
+        );
+
+        // An entity linked into a leaf it does not overlap.
+        let mut qt = build()?;
+        qt.link(leaf + 1, 0);
+        assert_eq!(
+            qt.validate(),
+            Err(IntegrityError::EntityWronglyLinked {
+                entity: 0,
+                node: leaf + 1,
+            })
+        );
+
+        // A link that was freed but is still reachable.
+        let mut qt = build()?;
+        let enode = qt.nodes.get(leaf).first_entity.unwrap();
+        qt.entity_nodes.erase(enode);
+        assert_eq!(
+            qt.validate(),
+            Err(IntegrityError::VacantEntityNode {
+                node: leaf,
+                entity_node: enode,
+            })
+        );
+
+        // Children that were freed while their parent is still a branch.
+        let mut qt = build()?;
+        qt.nodes.erase(leaf + 2);
+        assert!(matches!(qt.validate(), Err(IntegrityError::VacantNode { node }) if node == leaf + 2));
+        Ok(())
+    }
+
+    #[test]
+    fn stats() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        assert_eq!(
+            qt.stats(),
+            QuadtreeStats {
+                nodes: 1,
+                leaves: 1,
+                depth_histogram: vec![1],
+                ..QuadtreeStats::default()
+            }
+        );
 
         /***
-        Second stage of cleanup removes the root branch
+        |-----------------------|
+        |  x  |     |           |
+        |-----|-----|           |
+        |  x  |     |           |
+        |-----------|-----------|
+        |   aaaaaaaaaaaaaaaa    |
+        |   aaaaaaaaaaaaaaaa    |
+        |           |           |
+        |-----------------------|
          */
+        qt.insert(-40.0, 10.0, 40.0, 30.0, ())?;
+        qt.insert(-40.0, -20.0, -30.0, -10.0, ())?;
+        let b = qt.insert(-20.0, -20.0, -10.0, -10.0, ())?;
+        qt.insert(-45.0, -45.0, -40.0, -40.0, ())?;
+        qt.remove(b)?;
+        let stats = qt.stats();
+        assert_eq!(stats.nodes, 9);
+        assert_eq!(stats.leaves, 7);
+        assert_eq!(stats.branches, 2);
+        assert_eq!(stats.depth_histogram, vec![0, 3, 4]);
+        assert_eq!(stats.max_entities_per_leaf, 1);
+        assert_eq!(stats.mean_entities_per_leaf, 4.0 / 7.0);
+        assert_eq!(stats.entities, 3);
+        assert_eq!(stats.entity_nodes, 4);
+        assert_eq!(stats.duplication_factor, 4.0 / 3.0);
+        assert_eq!(stats.free_nodes, 0);
+        assert_eq!(stats.free_entity_nodes, 1);
+        assert_eq!(stats.free_entities, 1);
+
+        // Collapsed nodes and their links go onto the free lists.
         qt.cleanup();
-        qt.traverse(&mut tv);
-        tv.assert_counts(0, 1, 0);
-        tv.reset();
+        let stats = qt.stats();
+        assert_eq!((stats.nodes, stats.leaves, stats.branches), (5, 4, 1));
+        assert_eq!(stats.max_entities_per_leaf, 2);
+        assert_eq!(stats.free_nodes, 4);
         Ok(())
     }
 }
@@ -0,0 +1,224 @@
+use std::collections::HashSet;
+use crate::coordinate::Coordinate;
+use crate::quadtree::{EntityHandle, Quadtree, Rect, Visitor};
+
+/// Stroke colors of node boundaries, picked by depth and repeated for
+/// deeper nodes.
+const DEPTH_COLORS: [&str; 6] = ["#222222", "#1f77b4", "#2ca02c", "#9467bd", "#8c564b", "#17becf"];
+
+/// What [`Quadtree::to_svg`] draws and how large.
+#[derive(Clone, Debug)]
+pub struct SvgOptions<S: Coordinate = f32> {
+    /// Pixels per unit of the tree's coordinates.
+    pub scale: f64,
+    /// Labels every entity with the index of its handle.
+    pub show_ids: bool,
+    /// A box outlined on top of everything else, such as the query being
+    /// debugged.
+    pub highlight: Option<Rect<S>>,
+}
+
+impl<S: Coordinate> Default for SvgOptions<S> {
+    fn default() -> Self {
+        Self {
+            scale: 1.0,
+            show_ids: false,
+            highlight: None,
+        }
+    }
+}
+
+/// The left, top, width and height of a box, in `f64`.
+type Bounds = (f64, f64, f64, f64);
+
+/// Collects the boxes to draw while the tree is traversed.
+#[derive(Default)]
+struct SvgVisitor {
+    root: Option<Bounds>,
+    nodes: Vec<(u8, Bounds)>,
+    entities: Vec<(usize, Bounds)>,
+    seen: HashSet<usize>,
+}
+
+impl SvgVisitor {
+    fn node<S: Coordinate>(&mut self, depth: u8, x: S, y: S, width: S, height: S) {
+        let bounds = Self::bounds(x, y, width, height);
+        if depth == 0 {
+            self.root = Some(bounds);
+        }
+        self.nodes.push((depth, bounds));
+    }
+
+    /// Converts a box given by its center and size.
+    fn bounds<S: Coordinate>(x: S, y: S, width: S, height: S) -> Bounds {
+        let (w, h) = (width.to_f64(), height.to_f64());
+        (x.to_f64() - w * 0.5, y.to_f64() - h * 0.5, w, h)
+    }
+}
+
+impl<T, S: Coordinate> Visitor<T, S> for SvgVisitor {
+    fn entity(
+        &mut self,
+        entity: EntityHandle,
+        _payload: &T,
+        _idx: usize,
+        _next_entity: Option<usize>,
+        x: S,
+        y: S,
+        width: S,
+        height: S,
+    ) {
+        // Entities split across leaves are visited once per leaf.
+        if self.seen.insert(entity.index) {
+            self.entities.push((entity.index, Self::bounds(x, y, width, height)));
+        }
+    }
+
+    fn leaf(
+        &mut self,
+        depth: u8,
+        _idx: usize,
+        _num_entities: usize,
+        _first_entity: Option<usize>,
+        x: S,
+        y: S,
+        width: S,
+        height: S,
+    ) {
+        self.node(depth, x, y, width, height);
+    }
+
+    fn branch(
+        &mut self,
+        depth: u8,
+        _idx: usize,
+        _first_leaf: usize,
+        x: S,
+        y: S,
+        width: S,
+        height: S,
+    ) {
+        self.node(depth, x, y, width, height);
+    }
+}
+
+fn rect_element((left, top, width, height): Bounds, style: &str) -> String {
+    format!(
+        "<rect x=\"{left}\" y=\"{top}\" width=\"{width}\" height=\"{height}\" {style} vector-effect=\"non-scaling-stroke\"/>\n"
+    )
+}
+
+impl<T, S: Coordinate> Quadtree<T, S> {
+    /// Renders the tree as an SVG document: node boundaries colored and
+    /// thinned by depth, entity boxes on top, and the optional labels and
+    /// highlighted box of `options`. The view covers the root, so parts of
+    /// entities outside of it are cut off.
+    ///
+    /// Boxes are drawn from the centers and sizes passed to the [`Visitor`],
+    /// which integer coordinates round down, so boxes with an odd size can
+    /// be drawn half a unit off.
+    pub fn to_svg(&self, options: &SvgOptions<S>) -> String {
+        let mut visitor = SvgVisitor::default();
+        self.traverse(&mut visitor);
+        let (left, top, width, height) = visitor.root.expect("the root is always visited");
+
+        let mut svg = format!(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"{left} {top} {width} {height}\" width=\"{}\" height=\"{}\">\n",
+            width * options.scale,
+            height * options.scale,
+        );
+
+        // Draw shallow nodes last so that their edges stay visible where
+        // deeper nodes share them.
+        visitor.nodes.sort_by_key(|&(depth, _)| std::cmp::Reverse(depth));
+        svg.push_str("<g fill=\"none\">\n");
+        for &(depth, bounds) in &visitor.nodes {
+            let color = DEPTH_COLORS[depth as usize % DEPTH_COLORS.len()];
+            let stroke_width = (2.0 - 0.25 * depth as f64).max(0.5);
+            let style = format!("stroke=\"{color}\" stroke-width=\"{stroke_width}\"");
+            svg.push_str(&rect_element(bounds, &style));
+        }
+        svg.push_str("</g>\n");
+
+        visitor.entities.sort_by_key(|&(index, _)| index);
+        svg.push_str("<g fill=\"#ff7f0e\" fill-opacity=\"0.3\" stroke=\"#ff7f0e\">\n");
+        for &(_, bounds) in &visitor.entities {
+            svg.push_str(&rect_element(bounds, "stroke-width=\"1\""));
+        }
+        svg.push_str("</g>\n");
+
+        if options.show_ids {
+            // Keep the labels 10 pixels tall however the tree is scaled.
+            let font_size = 10.0 / options.scale;
+            svg.push_str(&format!(
+                "<g font-family=\"monospace\" font-size=\"{font_size}\" text-anchor=\"middle\" dominant-baseline=\"central\">\n"
+            ));
+            for &(index, (left, top, width, height)) in &visitor.entities {
+                let (x, y) = (left + width * 0.5, top + height * 0.5);
+                svg.push_str(&format!("<text x=\"{x}\" y=\"{y}\">{index}</text>\n"));
+            }
+            svg.push_str("</g>\n");
+        }
+
+        if let Some(rect) = options.highlight {
+            let (x1, y1) = (rect.x1.to_f64(), rect.y1.to_f64());
+            let bounds = (x1, y1, rect.x2.to_f64() - x1, rect.y2.to_f64() - y1);
+            let style = "fill=\"none\" stroke=\"#d62728\" stroke-width=\"2\" stroke-dasharray=\"6 3\"";
+            svg.push_str(&rect_element(bounds, style));
+        }
+        svg.push_str("</svg>\n");
+        svg
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quadtree::QuadtreeError;
+
+    #[test]
+    fn empty_tree() -> Result<(), QuadtreeError> {
+        let qt = Quadtree::<(), i32>::new(0, 0, 100, 50, 4)?;
+        let svg = qt.to_svg(&SvgOptions {
+            scale: 2.0,
+            ..SvgOptions::default()
+        });
+        assert!(svg.starts_with(
+            "<svg xmlns=\"http://www.w3.org/2000/svg\" viewBox=\"-50 -25 100 50\" width=\"200\" height=\"100\">"
+        ));
+        assert!(svg.ends_with("</svg>\n"));
+        assert_eq!(svg.matches("<rect").count(), 1);
+        assert!(!svg.contains("<text"));
+        Ok(())
+    }
+
+    #[test]
+    fn nodes_entities_and_labels() -> Result<(), QuadtreeError> {
+        let mut qt = Quadtree::new(0.0, 0.0, 100.0, 100.0, 2)?;
+        qt.insert(-40.0, -40.0, 40.0, 40.0, ())?;
+        qt.insert(10.0, 10.0, 20.0, 20.0, ())?;
+        qt.insert(-30.0, -30.0, -20.0, -20.0, ())?;
+
+        // 5 nodes, and the large entity is drawn once although it is in
+        // every leaf.
+        let svg = qt.to_svg(&SvgOptions {
+            show_ids: true,
+            highlight: Some(Rect {
+                x1: 5.0,
+                y1: 5.0,
+                x2: 25.0,
+                y2: 25.0,
+            }),
+            ..SvgOptions::default()
+        });
+        assert_eq!(svg.matches("<rect").count(), 5 + 3 + 1);
+        assert_eq!(svg.matches(DEPTH_COLORS[0]).count(), 1);
+        assert_eq!(svg.matches(DEPTH_COLORS[1]).count(), 4);
+        assert!(svg.contains("<rect x=\"-40\" y=\"-40\" width=\"80\" height=\"80\" stroke-width=\"1\""));
+        assert!(svg.contains("<text x=\"0\" y=\"0\">0</text>"));
+        assert!(svg.contains("<text x=\"15\" y=\"15\">1</text>"));
+        assert!(svg.contains("<text x=\"-25\" y=\"-25\">2</text>"));
+        assert!(svg.contains("<rect x=\"5\" y=\"5\" width=\"20\" height=\"20\" fill=\"none\" stroke=\"#d62728\""));
+        Ok(())
+    }
+}
@@ -0,0 +1,153 @@
+use crate::coordinate::Coordinate;
+use crate::octree::OctreeVisitor;
+use crate::quadtree::{EntityHandle, Visitor};
+
+/// Records what a traversal visits. Leaves and branches are recorded by
+/// depth, and the entity count of every leaf is kept in `leaf_entities`.
+#[derive(Debug, Default)]
+pub(crate) struct TestVisitor {
+    pub(crate) entities: Vec<usize>,
+    pub(crate) leaves: Vec<u8>,
+    pub(crate) leaf_entities: Vec<usize>,
+    pub(crate) branches: Vec<u8>,
+}
+
+impl TestVisitor {
+    pub(crate) fn new() -> Self {
+        Self::default()
+    }
+
+    pub(crate) fn reset(&mut self) {
+        self.entities.clear();
+        self.leaves.clear();
+        self.leaf_entities.clear();
+        self.branches.clear();
+        println!("\n/////// RESET ///////\n")
+    }
+
+    pub(crate) fn assert_counts(&self, entities: usize, leaves: usize, branches: usize) {
+        assert_eq!(self.entities.len(), entities);
+        assert_eq!(self.leaves.len(), leaves);
+        assert_eq!(self.branches.len(), branches);
+    }
+}
+
+impl<T, S: Coordinate> Visitor<T, S> for TestVisitor {
+    fn entity(
+        &mut self,
+        entity: EntityHandle,
+        _payload: &T,
+        idx: usize,
+        next_entity: Option<usize>,
+        _x: S,
+        _y: S,
+        _width: S,
+        _height: S,
+    ) {
+        println!("----[EN: {} idx:{idx}->{next_entity:?}]", entity.index);
+        self.entities.push(entity.index);
+    }
+
+    fn leaf(
+        &mut self,
+        depth: u8,
+        idx: usize,
+        num_entities: usize,
+        first_entity: Option<usize>,
+        x: S,
+        y: S,
+        w: S,
+        h: S,
+    ) {
+        println!("--[LF: {idx}, entities: {num_entities}, first_entity: {first_entity:?}, d:{depth}, x:{x:?}, y:{y:?}, w:{w:?}, h:{h:?}]");
+        self.leaves.push(depth);
+        self.leaf_entities.push(num_entities);
+    }
+
+    fn branch(
+        &mut self,
+        depth: u8,
+        idx: usize,
+        first_leaf: usize,
+        x: S,
+        y: S,
+        w: S,
+        h: S,
+    ) {
+        println!(
+            "[BR: {idx},  d:{depth}, first_leaf:{first_leaf}, x:{x:?}, y:{y:?}, w:{w:?}, h:{h:?}]"
+        );
+        self.branches.push(depth);
+    }
+}
+
+impl<T, S: Coordinate> OctreeVisitor<T, S> for TestVisitor {
+    fn entity(
+        &mut self,
+        entity: EntityHandle,
+        _payload: &T,
+        _idx: usize,
+        _next_entity: Option<usize>,
+        _x: S,
+        _y: S,
+        _z: S,
+        _width: S,
+        _height: S,
+        _depth: S,
+    ) {
+        self.entities.push(entity.index);
+    }
+
+    fn leaf(
+        &mut self,
+        level: u8,
+        _idx: usize,
+        num_entities: usize,
+        _first_entity: Option<usize>,
+        _x: S,
+        _y: S,
+        _z: S,
+        _width: S,
+        _height: S,
+        _depth: S,
+    ) {
+        self.leaves.push(level);
+        self.leaf_entities.push(num_entities);
+    }
+
+    fn branch(
+        &mut self,
+        level: u8,
+        _idx: usize,
+        _first_leaf: usize,
+        _x: S,
+        _y: S,
+        _z: S,
+        _width: S,
+        _height: S,
+        _depth: S,
+    ) {
+        self.branches.push(level);
+    }
+}
+
+pub(crate) fn ids<T>(results: Vec<(EntityHandle, &T)>) -> Vec<usize> {
+    results.into_iter().map(|(entity, _)| entity.index).collect()
+}
+
+/// Like [`ids`], for trees that report entities in no particular order.
+/// Takes any results, so iterators need not be collected first.
+pub(crate) fn sorted_ids<T>(results: impl IntoIterator<Item = (EntityHandle, T)>) -> Vec<usize> {
+    let mut ids: Vec<usize> = results.into_iter().map(|(entity, _)| entity.index).collect();
+    ids.sort();
+    ids
+}
+
+/// Returns a deterministic generator of numbers below the range it is
+/// called with, for scattering test data.
+pub(crate) fn lcg(mut seed: u32) -> impl FnMut(u32) -> u32 {
+    move |range| {
+        seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+        (seed >> 16) % range
+    }
+}